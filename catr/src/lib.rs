@@ -1,9 +1,18 @@
 use clap::{Arg, Command};
+use memchr::memchr;
+use memmap2::Mmap;
 use std::error::Error;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::time::UNIX_EPOCH;
 
 type MyResult<T> = Result<T, Box<dyn Error>>;
+const DEFAULT_HEADER_FORMAT: &str = "==> {name} <==";
+// regular files at least this big are memory-mapped instead of read
+const MMAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+// how much of the map is handed out per fill_buf, the file size is re-checked
+// before each window
+const MMAP_WINDOW: usize = 1024 * 1024;
 
 #[derive(Debug)]
 pub struct Config {
@@ -13,6 +22,12 @@ pub struct Config {
     // FIXME Option<> ?
     number_lines: bool,
     number_nonblank_lines: bool,
+    // template for the banner printed before each file, None means no banner
+    header_format: Option<String>,
+    separator: Option<String>,
+    // records end with NUL instead of newline
+    zero_terminated: bool,
+    mmap: bool,
 }
 
 impl Config {
@@ -22,6 +37,10 @@ impl Config {
             files,
             number_lines,
             number_nonblank_lines,
+            header_format: None,
+            separator: None,
+            zero_terminated: false,
+            mmap: true,
         }
     }
 }
@@ -46,6 +65,41 @@ pub fn get_args() -> MyResult<Config> {
                 .long("number-nonblank")
                 .help("Number nonblank lines"),
         )
+        .arg(
+            Arg::new("headers")
+                .long("headers")
+                .help("Print a '==> name <==' header before each file")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("header_format")
+                .long("header-format")
+                .value_name("FORMAT")
+                .help(
+                    "Header template, {name}, {size} and {mtime} are \
+                    replaced (implies --headers)",
+                ),
+        )
+        .arg(
+            Arg::new("separator")
+                .long("separator")
+                .value_name("STR")
+                .allow_hyphen_values(true)
+                .help("Print STR on a line of its own between files"),
+        )
+        .arg(
+            Arg::new("zero_terminated")
+                .short('z')
+                .long("zero-terminated")
+                .help("Line delimiter is NUL, not newline")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no_mmap")
+                .long("no-mmap")
+                .help("Never memory-map large files, always read them")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("files")
                 .value_parser(clap::value_parser!(String))
@@ -62,48 +116,286 @@ pub fn get_args() -> MyResult<Config> {
         .unwrap()
         .map(|v| v.to_owned())
         .collect();
-    Ok(Config::new(files, number, number_nonblank))
+    let header_format = matches
+        .get_one::<String>("header_format")
+        .cloned()
+        .or_else(|| {
+            matches
+                .get_flag("headers")
+                .then(|| DEFAULT_HEADER_FORMAT.to_string())
+        });
+    let separator = matches.get_one::<String>("separator").cloned();
+    let zero_terminated = matches.get_flag("zero_terminated");
+    let mmap = !matches.get_flag("no_mmap");
+    Ok(Config {
+        header_format,
+        separator,
+        zero_terminated,
+        mmap,
+        ..Config::new(files, number, number_nonblank)
+    })
+}
+
+// Hands out a memory-mapped file through BufRead. Touching pages past the end
+// of a file that was truncated after mapping kills us with SIGBUS, so the
+// file length is checked before every window and we fall back to plain reads
+// from the current offset as soon as it shrank. That narrows the race but
+// can't close it, see `new`. The same fallback picks up anything appended
+// after the map was created.
+struct MmapReader {
+    file: File,
+    map: Mmap,
+    pos: usize,
+    // end of the window known to be backed by the file
+    checked_end: usize,
+    fallback: Option<BufReader<File>>,
 }
 
-fn open(filename: &str) -> MyResult<Box<dyn BufRead>> {
+impl MmapReader {
+    fn new(file: File) -> io::Result<MmapReader> {
+        // SAFETY: the map is only read, but another process may still change
+        // or truncate the file while it is mapped, which nothing here can
+        // prevent. Checking the size before each window (see fill_buf) only
+        // narrows that: a truncation between the check and reading the window
+        // still raises SIGBUS and kills us, like other mmap-based tools.
+        // Files that may shrink while being read need --no-mmap.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(MmapReader {
+            file,
+            map,
+            pos: 0,
+            checked_end: 0,
+            fallback: None,
+        })
+    }
+
+    fn switch_to_fallback(&mut self) -> io::Result<()> {
+        let mut file = self.file.try_clone()?;
+        file.seek(SeekFrom::Start(self.pos as u64))?;
+        self.fallback = Some(BufReader::new(file));
+        Ok(())
+    }
+}
+
+impl Read for MmapReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for MmapReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.fallback.is_none() && self.pos >= self.checked_end {
+            let end = self.map.len().min(self.pos + MMAP_WINDOW);
+            if self.pos >= self.map.len() || self.file.metadata()?.len() < end as u64 {
+                self.switch_to_fallback()?;
+            } else {
+                self.checked_end = end;
+            }
+        }
+        match &mut self.fallback {
+            Some(reader) => reader.fill_buf(),
+            None => Ok(&self.map[self.pos..self.checked_end]),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match &mut self.fallback {
+            Some(reader) => reader.consume(amt),
+            None => self.pos = (self.pos + amt).min(self.checked_end),
+        }
+    }
+}
+
+fn open(filename: &str, mmap: bool) -> MyResult<Box<dyn BufRead>> {
     match filename {
         "-" => Ok(Box::new(BufReader::new(io::stdin()))),
-        _ => Ok(Box::new(BufReader::new(File::open(filename)?))),
+        _ => {
+            let file = File::open(filename)?;
+            let meta = file.metadata()?;
+            if mmap && meta.is_file() && meta.len() >= MMAP_THRESHOLD {
+                Ok(Box::new(MmapReader::new(file)?))
+            } else {
+                Ok(Box::new(BufReader::new(file)))
+            }
+        }
     }
 }
 
-fn read_file(fileio: Box<dyn BufRead>, config: &Config) -> MyResult<()>{
-    let mut idx = 1;
-    let need_to_print_idx = config.number_nonblank_lines || config.number_lines;
-    for line in fileio.lines() {
-        let l = line?;
-        if !need_to_print_idx {
-            println!("{}", l);
-            continue;
+// size in bytes and mtime in seconds since the epoch, "-" when unknown (stdin)
+fn file_stats(filename: &str) -> (String, String) {
+    let unknown = || String::from("-");
+    if filename == "-" {
+        return (unknown(), unknown());
+    }
+    match fs::metadata(filename) {
+        Ok(meta) => {
+            let mtime = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or_else(unknown, |d| d.as_secs().to_string());
+            (meta.len().to_string(), mtime)
         }
-        if l.is_empty() && config.number_nonblank_lines {
-            println!();
-            continue;
+        Err(_) => (unknown(), unknown()),
+    }
+}
+
+fn format_header(format: &str, filename: &str) -> String {
+    let (size, mtime) = file_stats(filename);
+    let mut header = String::new();
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        header.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find('}').map_or(rest.len(), |i| i + 1);
+        match &rest[..end] {
+            "{name}" => header.push_str(filename),
+            "{size}" => header.push_str(&size),
+            "{mtime}" => header.push_str(&mtime),
+            other => header.push_str(other),
         }
-        if !l.is_empty() && config.number_lines {
-            println!("{:>6}\t{}", idx, l);
-            idx += 1;
-            continue;
+        rest = &rest[end..];
+    }
+    header.push_str(rest);
+    header
+}
+
+// strip the terminator the way BufRead::lines does, "\r\n" counts as a newline
+fn trim_record(record: &[u8], delimiter: u8) -> &[u8] {
+    let record = record.strip_suffix(&[delimiter]).unwrap_or(record);
+    match delimiter {
+        b'\n' => record.strip_suffix(b"\r").unwrap_or(record),
+        _ => record,
+    }
+}
+
+fn write_record(
+    out: &mut impl Write,
+    record: &[u8],
+    idx: &mut usize,
+    config: &Config,
+) -> io::Result<()> {
+    let delimiter = if config.zero_terminated { b'\0' } else { b'\n' };
+    let l = trim_record(record, delimiter);
+    let need_to_print_idx = config.number_nonblank_lines || config.number_lines;
+    let skip_idx = l.is_empty() && config.number_nonblank_lines;
+    if need_to_print_idx && !skip_idx {
+        write!(out, "{:>6}\t", idx)?;
+        *idx += 1;
+    }
+    out.write_all(l)?;
+    out.write_all(&[delimiter])
+}
+
+// records are sliced straight out of the reader's buffer, only one that
+// straddles two buffers gets copied
+fn read_file(mut fileio: Box<dyn BufRead>, config: &Config) -> MyResult<()>{
+    let delimiter = if config.zero_terminated { b'\0' } else { b'\n' };
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut idx = 1;
+    let mut partial = Vec::new();
+    loop {
+        let buf = fileio.fill_buf()?;
+        if buf.is_empty() {
+            break;
         }
-        println!("{:>6}\t{}", idx, l);
-        idx += 1;
+        let mut start = 0;
+        while let Some(i) = memchr(delimiter, &buf[start..]) {
+            let end = start + i + 1;
+            if partial.is_empty() {
+                write_record(&mut out, &buf[start..end], &mut idx, config)?;
+            } else {
+                partial.extend_from_slice(&buf[start..end]);
+                write_record(&mut out, &partial, &mut idx, config)?;
+                partial.clear();
+            }
+            start = end;
+        }
+        partial.extend_from_slice(&buf[start..]);
+        let len = buf.len();
+        fileio.consume(len);
+    }
+    if !partial.is_empty() {
+        write_record(&mut out, &partial, &mut idx, config)?;
     }
+    out.flush()?;
     Ok(())
 }
 
 pub fn run(config: Config) -> MyResult<()> {
+    let mut first = true;
     for filename in &config.files {
-        match open(filename) {
+        match open(filename, config.mmap) {
             Err(err) => eprintln!("Failed to open {}: {}", filename, err),
             Ok(fileio) => {
+                if !first {
+                    if let Some(separator) = &config.separator {
+                        println!("{}", separator);
+                    } else if config.header_format.is_some() {
+                        println!();
+                    }
+                }
+                if let Some(format) = &config.header_format {
+                    println!("{}", format_header(format, filename));
+                }
+                first = false;
                 read_file(fileio, &config)?;
             },
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::{MmapReader, MMAP_WINDOW};
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Write};
+    use tempfile::NamedTempFile;
+
+    // a file two windows and a bit long, mapped
+    fn mapped_file() -> (NamedTempFile, Vec<u8>, MmapReader) {
+        let mut file = NamedTempFile::new().unwrap();
+        let contents: Vec<u8> = (0..2 * MMAP_WINDOW + 10).map(|i| (i % 251) as u8).collect();
+        file.write_all(&contents).unwrap();
+        let reader = MmapReader::new(File::open(file.path()).unwrap()).unwrap();
+        (file, contents, reader)
+    }
+
+    #[test]
+    fn test_truncated_after_mapping() {
+        let (file, contents, mut reader) = mapped_file();
+        let mut first = vec![0; MMAP_WINDOW];
+        reader.read_exact(&mut first).unwrap();
+        assert_eq!(first, contents[..MMAP_WINDOW]);
+        assert!(reader.fallback.is_none());
+
+        file.as_file().set_len(MMAP_WINDOW as u64 + 100).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(reader.fallback.is_some());
+        assert_eq!(rest, contents[MMAP_WINDOW..MMAP_WINDOW + 100]);
+    }
+
+    #[test]
+    fn test_appended_after_mapping() {
+        let (file, contents, mut reader) = mapped_file();
+        OpenOptions::new()
+            .append(true)
+            .open(file.path())
+            .unwrap()
+            .write_all(b"more\n")
+            .unwrap();
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert!(reader.fallback.is_some());
+        assert_eq!(all[..contents.len()], contents[..]);
+        assert_eq!(&all[contents.len()..], b"more\n");
+    }
+}
@@ -3,6 +3,8 @@ use predicates::prelude::*;
 use rand::{distributions::Alphanumeric, Rng};
 use std::error::Error;
 use std::fs;
+use std::io::Write;
+use tempfile::NamedTempFile;
 
 type TestResult = Result<(), Box<dyn Error>>;
 
@@ -11,6 +13,7 @@ const EMPTY: &str = "tests/inputs/empty.txt";
 const FOX: &str = "tests/inputs/fox.txt";
 const SPIDERS: &str = "tests/inputs/spiders.txt";
 const BUSTLE: &str = "tests/inputs/the-bustle.txt";
+const RECORDS: &str = "tests/inputs/records.txt";
 
 #[test]
 fn usage() -> TestResult {
@@ -173,3 +176,124 @@ fn all_b() -> TestResult {
     run(&[FOX, SPIDERS, BUSTLE, "-b"],
         "tests/expected/all.b.out")
 }
+
+#[test]
+fn all_headers() -> TestResult {
+    run(&["--headers", FOX, SPIDERS, BUSTLE],
+        "tests/expected/all.headers.out")
+}
+
+#[test]
+fn all_headers_n() -> TestResult {
+    run(&["--headers", "-n", FOX, SPIDERS, BUSTLE],
+        "tests/expected/all.headers.n.out")
+}
+
+#[test]
+fn all_separator() -> TestResult {
+    run(&["--separator", "-----", FOX, SPIDERS, BUSTLE],
+        "tests/expected/all.separator.out")
+}
+
+#[test]
+fn all_header_format() -> TestResult {
+    run(&["--header-format", "# {name} ({size} bytes)", FOX, SPIDERS, BUSTLE],
+        "tests/expected/all.header-format.out")
+}
+
+#[test]
+fn headers_and_separator() -> TestResult {
+    run(&["--headers", "--separator", "-----", FOX, SPIDERS],
+        "tests/expected/fox-spiders.headers.separator.out")
+}
+
+#[test]
+fn separator_ends_line() -> TestResult {
+    let expected = fs::read_to_string("tests/expected/fox-spiders.headers.separator.out")?
+        .replace("-----", "--");
+    Command::cargo_bin(PRG)?
+        .args(["--headers", "--separator", "--", FOX, SPIDERS])
+        .assert()
+        .success()
+        .stdout(expected);
+    Ok(())
+}
+
+#[test]
+fn header_format_stdin() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--header-format", "{name} {size} {mtime} {other}", "-"])
+        .write_stdin("hello\n")
+        .assert()
+        .success()
+        .stdout("- - - {other}\nhello\n");
+    Ok(())
+}
+
+#[test]
+fn header_format_mtime() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--header-format", "{mtime}", FOX])
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match("^[0-9]+\nThe quick")?);
+    Ok(())
+}
+
+#[test]
+fn records_z() -> TestResult {
+    run(&["-z", RECORDS], "tests/expected/records.txt.z.out")
+}
+
+#[test]
+fn records_z_n() -> TestResult {
+    run(&["--zero-terminated", "-n", RECORDS],
+        "tests/expected/records.txt.z.n.out")
+}
+
+#[test]
+fn records_z_b() -> TestResult {
+    run(&["-z", "-b", RECORDS], "tests/expected/records.txt.z.b.out")
+}
+
+#[test]
+fn records_z_stdin_b() -> TestResult {
+    run_stdin(RECORDS, &["-z", "-b"], "tests/expected/records.txt.z.b.out")
+}
+
+#[test]
+fn records_n() -> TestResult {
+    run(&["-n", RECORDS], "tests/expected/records.txt.n.out")
+}
+
+// big enough to take the memory-mapped path
+fn gen_big_file() -> Result<(NamedTempFile, String), Box<dyn Error>> {
+    let mut file = NamedTempFile::new()?;
+    let mut expected = String::new();
+    let mut contents = String::new();
+    for i in 0..400_000 {
+        let line = format!("line {} {}", i, "x".repeat(40));
+        contents.push_str(&line);
+        contents.push('\n');
+        expected.push_str(&format!("{:>6}\t{}\n", i + 1, line));
+    }
+    // no trailing newline on the last line
+    contents.push_str("last");
+    expected.push_str("400001\tlast\n");
+    file.write_all(contents.as_bytes())?;
+    Ok((file, expected))
+}
+
+#[test]
+fn big_file_mmap() -> TestResult {
+    let (file, expected) = gen_big_file()?;
+    for args in [&["-n"][..], &["-n", "--no-mmap"]] {
+        Command::cargo_bin(PRG)?
+            .args(args)
+            .arg(file.path())
+            .assert()
+            .success()
+            .stdout(expected.clone());
+    }
+    Ok(())
+}
@@ -1,26 +1,109 @@
-use clap::{Arg, ArgAction, Command};
-use std::fs::File;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use clap::{parser::ValueSource, Arg, ArgAction, Command};
+use regex::bytes::Regex;
+use serde_json::{json, Value};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use std::{
     error::Error,
-    io::{self, BufRead, BufReader, Read},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
 };
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 type MyResult<T> = Result<T, Box<dyn Error>>;
 const COUNT_DEFAULT_VALUE: &str = "10";
+const DEFAULT_HEADER_FORMAT: &str = "==> {name} <==";
+const BLOCK_SIZE: usize = 64 * 1024;
+// how often --wait looks at a file again
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// exit status when --timeout ran out, the same as timeout(1)
+pub const TIMEOUT_STATUS: i32 = 124;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Count {
+    // K
+    First(usize),
+    // -K
+    AllButLast(usize),
+    // K%, in millionths so 100% is 1_000_000
+    Percent(u32),
+}
+
+// how heading one file went, anything wrong has been reported already
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Done,
+    Failed,
+    TimedOut,
+}
+
+#[derive(Debug)]
+pub enum Separator {
+    Byte(u8),
+    Regex(Regex),
+}
 
 #[derive(Debug)]
 pub struct Config {
     files: Vec<String>,
-    lines: usize,
-    bytes: Option<usize>,
+    lines: Count,
+    bytes: Option<Count>,
+    chars: Option<usize>,
+    graphemes: Option<usize>,
+    print_headers: bool,
+    header_format: String,
+    // don't read ahead on a piped stdin
+    exact: bool,
+    // what ends a line for -n, b'\0' with -z
+    separator: Separator,
+    // stop at the first line matching `until`, or not matching `while_`
+    until: Option<Regex>,
+    until_inclusive: bool,
+    while_: Option<Regex>,
+    // lines, or bytes with -c, to drop before counting
+    skip: usize,
+    // with --table, the field delimiter or None to detect it
+    table: Option<Option<u8>>,
+    // poll a regular file until the lines or bytes to print are there
+    wait: bool,
+    timeout: Option<Duration>,
+    // files to read at the same time, output stays in argument order
+    jobs: usize,
+    // gunzip and friends, by magic bytes
+    decompress: bool,
+    // a JSON object per file instead of headers and raw output
+    json: bool,
 }
 
 impl Config {
-    pub fn new(files: Vec<String>, lines: usize, bytes: Option<usize>) -> Self {
+    pub fn new(files: Vec<String>, lines: Count, bytes: Option<Count>) -> Self {
         Config {
+            print_headers: files.len() > 1,
             files,
             lines,
             bytes,
+            chars: None,
+            graphemes: None,
+            header_format: DEFAULT_HEADER_FORMAT.to_string(),
+            exact: false,
+            separator: Separator::Byte(b'\n'),
+            until: None,
+            until_inclusive: false,
+            while_: None,
+            skip: 0,
+            table: None,
+            wait: false,
+            timeout: None,
+            jobs: 1,
+            decompress: false,
+            json: false,
         }
     }
 }
@@ -35,12 +118,16 @@ pub fn get_args() -> MyResult<Config> {
                 .short('c')
                 .long("bytes")
                 .action(ArgAction::Set)
-                .value_parser(clap::value_parser!(usize))
+                .value_parser(|value: &str| parse_count(value, "byte"))
+                .allow_hyphen_values(true)
                 .conflicts_with("lines")
                 .help(
-                    "print the first K bytes of each file\
-                with the leading '-', print all but the last\
-                K bytes of each file",
+                    "print the first K bytes of each file; \
+                with the leading '-', print all but the last \
+                K bytes of each file; K may have a multiplier \
+                suffix: b 512, kB 1000, K 1024, MB 1000*1000, \
+                M 1024*1024, and so on for G, T, P, E, Z, Y, R, Q; \
+                binary prefixes like KiB=K also work",
                 ),
         )
         .arg(
@@ -49,11 +136,270 @@ pub fn get_args() -> MyResult<Config> {
                 .long("lines")
                 .action(ArgAction::Set)
                 .default_value(COUNT_DEFAULT_VALUE) // FIXME cannot pass string. must be 'static
-                .value_parser(clap::value_parser!(usize))
+                .value_parser(|value: &str| parse_count(value, "line"))
+                .allow_hyphen_values(true)
                 .help(
                     "print the first K lines instead of the \
                 first 10; with the leading '-', print all but the \
-                last K lines of each file",
+                last K lines of each file; K takes the same \
+                suffixes as --bytes",
+                ),
+        )
+        .arg(
+            Arg::new("chars")
+                .long("chars")
+                .value_name("K")
+                .action(ArgAction::Set)
+                .value_parser(|value: &str| parse_positive(value, "character"))
+                .conflicts_with_all(["lines", "bytes"])
+                .help(
+                    "print the first K characters (Unicode scalar values) \
+                of each file without splitting any of them",
+                ),
+        )
+        .arg(
+            Arg::new("graphemes")
+                .long("graphemes")
+                .value_name("K")
+                .action(ArgAction::Set)
+                .value_parser(|value: &str| parse_positive(value, "grapheme"))
+                .conflicts_with_all(["lines", "bytes", "chars"])
+                .help("print the first K grapheme clusters of each file"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .visible_alias("silent")
+                .action(ArgAction::SetTrue)
+                .overrides_with("verbose")
+                .help("never print headers giving file names"),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .action(ArgAction::SetTrue)
+                .overrides_with("quiet")
+                .help("always print headers giving file names"),
+        )
+        .arg(
+            Arg::new("header_format")
+                .long("header-format")
+                .value_name("FORMAT")
+                .help(
+                    "header template; {name}, {size} and {mtime} are \
+                replaced by the file name, its size in bytes and its \
+                modification time in seconds since the epoch",
+                ),
+        )
+        .arg(
+            Arg::new("percent")
+                .short('p')
+                .long("percent")
+                .value_name("PCT")
+                .value_parser(|value: &str| {
+                    let value = value.strip_suffix('%').unwrap_or(value);
+                    parse_percent(&format!("{}%", value))
+                        .ok_or_else(|| format!("Illegal percentage -- {}", value))
+                })
+                .conflicts_with_all(["lines", "bytes", "chars", "graphemes"])
+                .help(
+                    "print the first PCT percent of the lines of each file \
+                (rounded up), same as -n PCT%; -c PCT% does it for bytes",
+                ),
+        )
+        .arg(
+            Arg::new("skip")
+                .long("skip")
+                .value_name("N")
+                .value_parser(|value: &str| {
+                    parse_size(value).map_err(|e| size_error_message(e, "skip", value))
+                })
+                .conflicts_with_all(["chars", "graphemes"])
+                .help(
+                    "skip the first N lines (or bytes with -c) before \
+                printing; N takes the same suffixes as --bytes",
+                ),
+        )
+        .arg(
+            Arg::new("range")
+                .long("range")
+                .value_name("START:END")
+                .value_parser(parse_range)
+                .conflicts_with_all(["lines", "bytes", "chars", "graphemes", "skip", "percent"])
+                .help(
+                    "print lines START to END, counting from 1 and \
+                including both; either one may be left out",
+                ),
+        )
+        .arg(
+            Arg::new("byte_range")
+                .long("byte-range")
+                .value_name("START:END")
+                .value_parser(parse_range)
+                .conflicts_with_all([
+                    "lines",
+                    "bytes",
+                    "chars",
+                    "graphemes",
+                    "skip",
+                    "range",
+                    "percent",
+                ])
+                .help("print bytes START to END, like --range"),
+        )
+        .arg(
+            Arg::new("zero_terminated")
+                .short('z')
+                .long("zero-terminated")
+                .action(ArgAction::SetTrue)
+                .help("line delimiter is NUL, not newline"),
+        )
+        .arg(
+            Arg::new("delimiter")
+                .short('d')
+                .long("delimiter")
+                .value_name("DELIM")
+                .allow_hyphen_values(true)
+                .value_parser(parse_delimiter)
+                .conflicts_with("zero_terminated")
+                .help("use the single byte DELIM to end lines instead of newline"),
+        )
+        .arg(
+            Arg::new("record_separator")
+                .long("record-separator")
+                .value_name("REGEX")
+                .allow_hyphen_values(true)
+                .value_parser(|value: &str| Regex::new(value).map_err(|e| e.to_string()))
+                // a match is only known to be over once the byte after it is
+                // read, and that byte can't be put back into a pipe
+                .conflicts_with_all(["zero_terminated", "delimiter", "exact"])
+                .help("lines end with whatever matches REGEX"),
+        )
+        .arg(
+            Arg::new("until")
+                .long("until")
+                .value_name("REGEX")
+                .allow_hyphen_values(true)
+                .value_parser(|value: &str| Regex::new(value).map_err(|e| e.to_string()))
+                .conflicts_with_all(["bytes", "chars", "graphemes"])
+                .help(
+                    "stop before the first line matching REGEX; \
+                without -n there is no line limit",
+                ),
+        )
+        .arg(
+            Arg::new("inclusive")
+                .long("inclusive")
+                .action(ArgAction::SetTrue)
+                .requires("until")
+                .help("with --until, also print the matching line"),
+        )
+        .arg(
+            Arg::new("while")
+                .long("while")
+                .value_name("REGEX")
+                .allow_hyphen_values(true)
+                .value_parser(|value: &str| Regex::new(value).map_err(|e| e.to_string()))
+                .conflicts_with_all(["bytes", "chars", "graphemes"])
+                .help(
+                    "print lines as long as they match REGEX; \
+                without -n there is no line limit",
+                ),
+        )
+        .arg(
+            Arg::new("table")
+                .long("table")
+                .value_name("DELIM")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("")
+                .value_parser(parse_table_delimiter)
+                .conflicts_with_all([
+                    "bytes",
+                    "chars",
+                    "graphemes",
+                    "byte_range",
+                    "percent",
+                    "zero_terminated",
+                    "delimiter",
+                    "record_separator",
+                    "until",
+                    "while",
+                    // the csv reader buffers ahead of what it shows
+                    "exact",
+                ])
+                .help(
+                    "show CSV/TSV as aligned columns: the header row and \
+                then -n rows; DELIM (\\t for tab) is detected when left out",
+                ),
+        )
+        .arg(
+            Arg::new("wait")
+                .long("wait")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["chars", "graphemes", "percent", "table", "until", "while"])
+                .help(
+                    "wait for a file that is still being written to have the \
+                lines (or bytes with -c) to print",
+                ),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("DURATION")
+                .value_parser(parse_duration)
+                .requires("wait")
+                .help(
+                    "with --wait, give up on a file after DURATION seconds \
+                (or ms, s, m, h) and exit with status 124",
+                ),
+        )
+        .arg(
+            Arg::new("jobs")
+                .short('j')
+                .long("jobs")
+                .value_name("N")
+                .value_parser(|value: &str| parse_positive(value, "job"))
+                .help(
+                    "read up to N files at the same time; output, headers and \
+                errors still come in argument order",
+                ),
+        )
+        .arg(
+            Arg::new("decompress")
+                .long("decompress")
+                .action(ArgAction::SetTrue)
+                // the magic number is read, and decoders read ahead
+                .conflicts_with_all(["wait", "exact"])
+                .help(
+                    "read gzip, bzip2, xz and zstd files as what they hold; \
+                other files are read as they are",
+                ),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["verbose", "header_format", "chars", "graphemes", "table"])
+                .help(
+                    "print a JSON object per line for each file: its name, \
+                lines (or base64 bytes with -c) and their count, whether \
+                there was more (null when --exact can't tell on a pipe), \
+                and an error if it could not be read",
+                ),
+        )
+        .arg(
+            Arg::new("exact")
+                .long("exact")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "read a piped stdin one byte at a time so that nothing \
+                after the printed data is consumed (a seekable stdin is \
+                always left right after it); on a pipe, --until and \
+                --while still consume the line they stop at, and \
+                --graphemes the character after the last cluster",
                 ),
         )
         .arg(
@@ -65,65 +411,1355 @@ pub fn get_args() -> MyResult<Config> {
         )
         .get_matches();
 
-    let bytes: Option<usize> = matches.get_one("bytes").copied();
-    if let Some(bytes) = bytes {
-        if bytes == 0 {
-            return Err(From::from("Illegal byte count -- 0"));
-        }
+    let mut bytes: Option<Count> = matches.get_one("bytes").copied();
+    if bytes == Some(Count::First(0)) {
+        return Err(From::from("Illegal byte count -- 0"));
     }
 
-    let lines = *matches.get_one("lines").expect("cannot get lines");
-    if lines == 0 {
+    let mut lines = *matches.get_one("lines").expect("cannot get lines");
+    if lines == Count::First(0) {
         return Err(From::from("Illegal line count -- 0"));
     }
 
+    let until: Option<Regex> = matches.get_one("until").cloned();
+    let while_: Option<Regex> = matches.get_one("while").cloned();
+    if until.is_some() || while_.is_some() {
+        match lines {
+            Count::AllButLast(_) => {
+                return Err(From::from(
+                    "--until and --while cannot be used with a negative line count",
+                ))
+            }
+            _ if matches.value_source("lines") == Some(ValueSource::DefaultValue) => {
+                lines = Count::First(usize::MAX);
+            }
+            _ => {}
+        }
+    }
+
+    let mut skip = matches.get_one::<usize>("skip").copied().unwrap_or(0);
+    let range_count = |start: usize, end: Option<usize>| {
+        Count::First(end.map_or(usize::MAX, |end| end - start + 1))
+    };
+    if let Some(&percent) = matches.get_one::<u32>("percent") {
+        lines = Count::Percent(percent);
+    }
+    if let Some(&(start, end)) = matches.get_one::<(usize, Option<usize>)>("range") {
+        skip = start - 1;
+        lines = range_count(start, end);
+    }
+    if let Some(&(start, end)) = matches.get_one::<(usize, Option<usize>)>("byte_range") {
+        skip = start - 1;
+        bytes = Some(range_count(start, end));
+    }
+
+    let table: Option<Option<u8>> = matches.get_one("table").copied();
+    if table.is_some() && !matches!(lines, Count::First(_)) {
+        return Err(From::from(
+            "--table cannot be used with a negative line count",
+        ));
+    }
+
+    let wait = matches.get_flag("wait");
+    let bounded = |count: Option<Count>| matches!(count, Some(Count::First(n)) if n != usize::MAX);
+    if wait && !bounded(bytes.or(Some(lines))) {
+        return Err(From::from("--wait needs a positive line or byte count"));
+    }
+
+    let chars: Option<usize> = matches.get_one("chars").copied();
+    let graphemes: Option<usize> = matches.get_one("graphemes").copied();
+
     let files: Vec<String> = matches.get_many("files").unwrap().cloned().collect();
     //println!("Config: {:?} {} {:#?}", bytes, lines, files);
-    let final_config = Config::new(files, lines, bytes);
+    let default_config = Config::new(files, lines, bytes);
+    let json = matches.get_flag("json");
+    let print_headers = match (matches.get_flag("quiet"), matches.get_flag("verbose")) {
+        _ if json => false,
+        (true, _) => false,
+        (_, true) => true,
+        _ => default_config.print_headers,
+    };
+    let header_format = matches
+        .get_one::<String>("header_format")
+        .cloned()
+        .unwrap_or_else(|| default_config.header_format.clone());
+    let separator = if let Some(regex) = matches.get_one::<Regex>("record_separator") {
+        Separator::Regex(regex.clone())
+    } else if let Some(&delimiter) = matches.get_one::<u8>("delimiter") {
+        Separator::Byte(delimiter)
+    } else if matches.get_flag("zero_terminated") {
+        Separator::Byte(b'\0')
+    } else {
+        default_config.separator
+    };
+    let final_config = Config {
+        exact: matches.get_flag("exact"),
+        separator,
+        until,
+        until_inclusive: matches.get_flag("inclusive"),
+        while_,
+        skip,
+        table,
+        wait,
+        timeout: matches.get_one("timeout").copied(),
+        jobs: matches.get_one("jobs").copied().unwrap_or(1),
+        decompress: matches.get_flag("decompress"),
+        json,
+        chars,
+        graphemes,
+        print_headers,
+        header_format,
+        ..default_config
+    };
     //println!("Config debug: {:#?}", final_config);
     Ok(final_config)
 }
 
-fn open(filename: &str) -> MyResult<Box<dyn BufRead>> {
-    match filename {
-        "-" => Ok(Box::new(BufReader::new(io::stdin()))),
-        _ => Ok(Box::new(BufReader::new(File::open(filename)?))),
+// "1.5" seconds, or with an ms, s, m or h suffix
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let seconds = match unit {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("Illegal duration -- {}", value)),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .and_then(|n| Duration::try_from_secs_f64(n * seconds).ok())
+        .ok_or_else(|| format!("Illegal duration -- {}", value))
+}
+
+// "START:END", counted from 1 and inclusive, either side may be left out
+fn parse_range(value: &str) -> Result<(usize, Option<usize>), String> {
+    let err = || format!("Illegal range -- {}", value);
+    let (start, end) = value.split_once(':').ok_or_else(err)?;
+    let start = match start {
+        "" => 1,
+        _ => parse_size(start).map_err(|_| err())?,
+    };
+    let end = match end {
+        "" => None,
+        _ => Some(parse_size(end).map_err(|_| err())?),
+    };
+    if start == 0 || end.is_some_and(|end| end < start) {
+        return Err(err());
+    }
+    Ok((start, end))
+}
+
+fn parse_delimiter(value: &str) -> Result<u8, String> {
+    match value.as_bytes() {
+        [delimiter] => Ok(*delimiter),
+        _ => Err(String::from("the delimiter must be a single byte")),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum SizeError {
+    Invalid,
+    TooLarge,
+}
+
+// multiplier for GNU head's size suffixes: b is 512, K/KiB 1024, KB 1000,
+// and likewise up the chain of M, G, T, P, E, Z, Y, R, Q
+fn suffix_multiplier(suffix: &str) -> Result<usize, SizeError> {
+    const PREFIXES: &str = "KMGTPEZYRQ";
+    let mut chars = suffix.chars();
+    let prefix = match chars.next() {
+        None => return Ok(1),
+        Some('b') if suffix.len() == 1 => return Ok(512),
+        Some('k') => 'K',
+        Some('m') => 'M',
+        Some(prefix) => prefix,
+    };
+    let exponent = PREFIXES.find(prefix).ok_or(SizeError::Invalid)? + 1;
+    let base: usize = match chars.as_str() {
+        "" | "iB" => 1024,
+        "B" => 1000,
+        _ => return Err(SizeError::Invalid),
+    };
+    base.checked_pow(exponent as u32).ok_or(SizeError::TooLarge)
+}
+
+fn parse_size(value: &str) -> Result<usize, SizeError> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (digits, suffix) = value.split_at(split);
+    if digits.is_empty() {
+        return Err(SizeError::Invalid);
+    }
+    let multiplier = suffix_multiplier(suffix)?;
+    // only digits are left, so parsing can fail on overflow alone
+    let number: usize = digits.parse().map_err(|_| SizeError::TooLarge)?;
+    number.checked_mul(multiplier).ok_or(SizeError::TooLarge)
+}
+
+fn size_error_message(e: SizeError, kind: &str, value: &str) -> String {
+    match e {
+        SizeError::Invalid => format!("Illegal {} count -- {}", kind, value),
+        SizeError::TooLarge => {
+            format!("Illegal {} count -- {}: value too large", kind, value)
+        }
+    }
+}
+
+// "12.5%" in millionths, at most four decimals and no more than 100%
+fn parse_percent(value: &str) -> Option<u32> {
+    let number = value.strip_suffix('%')?;
+    let (int, frac) = number.split_once('.').unwrap_or((number, ""));
+    let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if (int.is_empty() && frac.is_empty()) || frac.len() > 4 || !digits(int) || !digits(frac) {
+        return None;
+    }
+    let int: u32 = if int.is_empty() { 0 } else { int.parse().ok()? };
+    let frac: u32 = format!("{:0<4}", frac).parse().ok()?;
+    let percent = int.checked_mul(10_000)?.checked_add(frac)?;
+    (percent > 0 && percent <= 1_000_000).then_some(percent)
+}
+
+// "K", "-K" or "K%", `kind` is "line" or "byte" for the error message
+fn parse_count(value: &str, kind: &str) -> Result<Count, String> {
+    if value.ends_with('%') {
+        return parse_percent(value)
+            .map(Count::Percent)
+            .ok_or_else(|| format!("Illegal {} count -- {}", kind, value));
+    }
+    match value.strip_prefix('-') {
+        Some(count) => parse_size(count).map(Count::AllButLast),
+        None => parse_size(value).map(Count::First),
+    }
+    .map_err(|e| size_error_message(e, kind, value))
+}
+
+// "K" only, for counts that have no "all but the last" form
+fn parse_positive(value: &str, kind: &str) -> Result<usize, String> {
+    match parse_size(value) {
+        Ok(0) => Err(format!("Illegal {} count -- 0", kind)),
+        Ok(count) => Ok(count),
+        Err(e) => Err(size_error_message(e, kind, value)),
+    }
+}
+
+// size in bytes and mtime in seconds since the epoch, "-" when unknown (stdin)
+fn file_stats(filename: &str) -> (String, String) {
+    let unknown = || String::from("-");
+    if filename == "-" {
+        return (unknown(), unknown());
+    }
+    match fs::metadata(filename) {
+        Ok(meta) => {
+            let mtime = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or_else(unknown, |d| d.as_secs().to_string());
+            (meta.len().to_string(), mtime)
+        }
+        Err(_) => (unknown(), unknown()),
+    }
+}
+
+fn format_header(format: &str, filename: &str) -> String {
+    let (size, mtime) = file_stats(filename);
+    let mut header = String::new();
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        header.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find('}').map_or(rest.len(), |i| i + 1);
+        match &rest[..end] {
+            "{name}" => header.push_str(filename),
+            "{size}" => header.push_str(&size),
+            "{mtime}" => header.push_str(&mtime),
+            other => header.push_str(other),
+        }
+        rest = &rest[end..];
+    }
+    header.push_str(rest);
+    header
+}
+
+enum Input {
+    // regular file, -n -K and -c -K seek in it instead of buffering;
+    // `start` is where reading began, not 0 for a shared stdin
+    File { file: File, start: u64 },
+    Stream(Box<dyn BufRead>),
+}
+
+impl Input {
+    fn into_reader(self) -> Box<dyn BufRead> {
+        match self {
+            Input::File { file, .. } => Box::new(BufReader::new(file)),
+            Input::Stream(reader) => reader,
+        }
+    }
+}
+
+// stdin as a File of its own, so it can be seeked and read without the
+// read-ahead of io::stdin()'s buffer; the duplicate shares the file offset
+#[cfg(not(windows))]
+fn stdin_file() -> io::Result<File> {
+    use std::os::fd::AsFd;
+    Ok(File::from(io::stdin().as_fd().try_clone_to_owned()?))
+}
+
+#[cfg(windows)]
+fn stdin_file() -> io::Result<File> {
+    use std::os::windows::io::AsHandle;
+    Ok(File::from(io::stdin().as_handle().try_clone_to_owned()?))
+}
+
+// `exact` reads a piped stdin a byte at a time so nothing past the output
+// is consumed
+fn open(filename: &str, exact: bool) -> MyResult<Input> {
+    let file = match filename {
+        "-" => stdin_file()?,
+        _ => File::open(filename)?,
+    };
+    if file.metadata()?.is_file() {
+        let start = (&file).stream_position()?;
+        Ok(Input::File { file, start })
+    } else if filename != "-" {
+        Ok(Input::Stream(Box::new(BufReader::new(file))))
+    } else if exact {
+        Ok(Input::Stream(Box::new(BufReader::with_capacity(1, file))))
+    } else {
+        Ok(Input::Stream(Box::new(BufReader::new(io::stdin()))))
+    }
+}
+
+// the longest magic number, xz's
+const MAGIC_LEN: usize = 6;
+
+#[derive(Debug, Clone, Copy)]
+enum Compression {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    fn detect(magic: &[u8]) -> Option<Self> {
+        match magic {
+            [0x1f, 0x8b, ..] => Some(Compression::Gzip),
+            [b'B', b'Z', b'h', ..] => Some(Compression::Bzip2),
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00] => Some(Compression::Xz),
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    // all of them take concatenated streams, like gzip -d does
+    fn decoder(self, reader: Box<dyn BufRead>) -> io::Result<Box<dyn Read>> {
+        Ok(match self {
+            Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+            Compression::Bzip2 => Box::new(bzip2::bufread::MultiBzDecoder::new(reader)),
+            Compression::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        })
+    }
+}
+
+// Swaps compressed input for a stream of what it holds. Decoding happens as
+// the output needs it, so only the start of a large file gets decompressed.
+fn decompress(input: Input) -> io::Result<Input> {
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+    let (compression, reader): (_, Box<dyn BufRead>) = match input {
+        Input::File { mut file, start } => {
+            (&mut file).take(MAGIC_LEN as u64).read_to_end(&mut magic)?;
+            file.seek(SeekFrom::Start(start))?;
+            match Compression::detect(&magic) {
+                Some(compression) => (compression, Box::new(BufReader::new(file))),
+                None => return Ok(Input::File { file, start }),
+            }
+        }
+        Input::Stream(mut reader) => {
+            reader
+                .by_ref()
+                .take(MAGIC_LEN as u64)
+                .read_to_end(&mut magic)?;
+            let compression = Compression::detect(&magic);
+            let reader = Box::new(io::Cursor::new(magic).chain(reader));
+            match compression {
+                Some(compression) => (compression, reader),
+                None => return Ok(Input::Stream(reader)),
+            }
+        }
+    };
+    Ok(Input::Stream(Box::new(BufReader::new(
+        compression.decoder(reader)?,
+    ))))
+}
+
+// counts what gets written, the output is always a prefix of the input so
+// this is also how much of it was used; `failed` tells an error writing the
+// output from one reading the input
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+    failed: bool,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf).inspect_err(|_| self.failed = true)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().inspect_err(|_| self.failed = true)
+    }
+}
+
+// Splits the input into lines that keep their separator, whatever it is
+struct Records<'a, R> {
+    reader: R,
+    separator: &'a Separator,
+    // read past the end of the last line, only for a regex separator
+    pending: Vec<u8>,
+}
+
+impl<'a, R: BufRead> Records<'a, R> {
+    fn new(reader: R, separator: &'a Separator) -> Self {
+        Records {
+            reader,
+            separator,
+            pending: Vec::new(),
+        }
+    }
+
+    // the reader and whatever was read past the last line
+    fn into_parts(self) -> (R, Vec<u8>) {
+        (self.reader, self.pending)
+    }
+
+    // Fills `record` with the next line and returns its length without the
+    // separator, or None at the end of input. A match of a regex separator
+    // that reaches the end of the data read so far might still grow, so it
+    // only counts once more data (or the end of input) follows it.
+    fn next(&mut self, record: &mut Vec<u8>) -> io::Result<Option<usize>> {
+        record.clear();
+        let regex = match self.separator {
+            Separator::Byte(byte) => {
+                if self.reader.read_until(*byte, record)? == 0 {
+                    return Ok(None);
+                }
+                return Ok(Some(record.strip_suffix(&[*byte]).unwrap_or(record).len()));
+            }
+            Separator::Regex(regex) => regex,
+        };
+        record.append(&mut self.pending);
+        let mut eof = false;
+        loop {
+            let found = regex
+                .find_iter(record)
+                .find(|m| !m.is_empty())
+                .map(|m| m.range());
+            if let Some(range) = found.filter(|range| eof || range.end < record.len()) {
+                self.pending.extend_from_slice(&record[range.end..]);
+                record.truncate(range.end);
+                return Ok(Some(range.start));
+            }
+            if eof {
+                return Ok(Some(record.len()).filter(|&len| len > 0));
+            }
+            let buf = self.reader.fill_buf()?;
+            eof = buf.is_empty();
+            record.extend_from_slice(buf);
+            let len = buf.len();
+            self.reader.consume(len);
+        }
+    }
+}
+
+// offset right after the line that is `count` lines before the end, a last
+// line without a delimiter still counts as a line
+fn all_but_last_lines_offset(
+    file: &mut File,
+    start: u64,
+    count: usize,
+    delimiter: u8,
+) -> io::Result<u64> {
+    let len = file.metadata()?.len().max(start);
+    if count == 0 {
+        return Ok(len);
     }
+    let mut buf = vec![0; BLOCK_SIZE];
+    let mut seen = 0;
+    let mut end = len;
+    while end > start {
+        let block_start = end.saturating_sub(BLOCK_SIZE as u64).max(start);
+        let chunk = &mut buf[..(end - block_start) as usize];
+        file.seek(SeekFrom::Start(block_start))?;
+        file.read_exact(chunk)?;
+        for (i, &byte) in chunk.iter().enumerate().rev() {
+            let pos = block_start + i as u64;
+            if byte == delimiter && pos + 1 != len {
+                seen += 1;
+                if seen == count {
+                    return Ok(pos + 1);
+                }
+            }
+        }
+        end = block_start;
+    }
+    Ok(start)
 }
 
-pub fn run(config: Config) -> MyResult<()> {
-    let num_files = config.files.len();
-    for (file_num, filename) in config.files.iter().enumerate() {
-        match open(filename) {
-            Err(err) => eprintln!("{}: {}\n", filename, err),
-            Ok(mut buf_read) => {
-                if num_files > 1 {
-                    println!(
-                        "{}==> {} <==",
-                        if file_num > 0 { "\n" } else { "" },
-                        filename
-                    );
+// copies `start..end` of the file
+fn copy_range(mut file: File, start: u64, end: u64, out: &mut impl Write) -> io::Result<()> {
+    file.seek(SeekFrom::Start(start))?;
+    io::copy(&mut file.take(end.saturating_sub(start)), out)?;
+    Ok(())
+}
+
+// keeps the last `count` lines back, so memory is bounded by those lines
+fn print_all_but_last_lines(
+    reader: impl BufRead,
+    count: usize,
+    separator: &Separator,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut records = Records::new(reader, separator);
+    let mut held = VecDeque::with_capacity(count + 1);
+    loop {
+        let mut line = Vec::new();
+        if records.next(&mut line)?.is_none() {
+            break;
+        }
+        held.push_back(line);
+        if held.len() > count {
+            if let Some(line) = held.pop_front() {
+                out.write_all(&line)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// keeps the last `count` bytes back, flushing the rest a block at a time
+fn print_all_but_last_bytes(
+    mut reader: impl BufRead,
+    count: usize,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut held = Vec::new();
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        held.extend_from_slice(buf);
+        let len = buf.len();
+        reader.consume(len);
+        if held.len() >= count + BLOCK_SIZE {
+            let excess = held.len() - count;
+            out.write_all(&held[..excess])?;
+            held.drain(..excess);
+        }
+    }
+    let excess = held.len().saturating_sub(count);
+    out.write_all(&held[..excess])
+}
+
+fn count_records(reader: impl BufRead, separator: &Separator) -> io::Result<u64> {
+    let mut records = Records::new(reader, separator);
+    let mut line = Vec::new();
+    let mut total = 0;
+    while records.next(&mut line)?.is_some() {
+        total += 1;
+    }
+    Ok(total)
+}
+
+// Turns a percentage into a number of lines, or bytes when `separator` is
+// None, rounding up. A regular file is measured (or read once to count its
+// lines) and then read again from the start; anything else is buffered.
+fn resolve_percent(
+    input: Input,
+    count: Count,
+    separator: Option<&Separator>,
+) -> MyResult<(Input, Count)> {
+    let Count::Percent(percent) = count else {
+        return Ok((input, count));
+    };
+    let (input, total) = match input {
+        Input::File { mut file, start } => {
+            let total = match separator {
+                None => file.metadata()?.len().saturating_sub(start),
+                Some(separator) => {
+                    let total = count_records(BufReader::new(&file), separator)?;
+                    file.seek(SeekFrom::Start(start))?;
+                    total
                 }
+            };
+            (Input::File { file, start }, total)
+        }
+        Input::Stream(mut reader) => {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf)?;
+            let total = match separator {
+                None => buf.len() as u64,
+                Some(separator) => count_records(buf.as_slice(), separator)?,
+            };
+            (Input::Stream(Box::new(io::Cursor::new(buf))), total)
+        }
+    };
+    let count = (total as u128 * percent as u128).div_ceil(1_000_000);
+    Ok((input, Count::First(count as usize)))
+}
 
-                if let Some(num_bytes) = config.bytes {
-                    let bytes = buf_read
-                        .bytes()
-                        .take(num_bytes)
-                        .collect::<Result<Vec<_>, _>>();
-                    print!("{}", String::from_utf8_lossy(&bytes?));
-                } else {
-                    let mut line = String::new();
-                    for _ in 0..config.lines {
-                        let bytes = buf_read.read_line(&mut line)?;
-                        if bytes == 0 {
-                            break;
-                        }
-                        print!("{}", line);
-                        line.clear();
+fn print_lines(input: Input, config: &Config, out: &mut impl Write) -> MyResult<()> {
+    let (input, count) = resolve_percent(input, config.lines, Some(&config.separator))?;
+    match (count, input, &config.separator) {
+        (Count::First(num_lines), input, separator) => {
+            let mut records = Records::new(input.into_reader(), separator);
+            let mut line = Vec::new();
+            for _ in 0..num_lines {
+                let Some(len) = records.next(&mut line)? else {
+                    break;
+                };
+                let text = &line[..len];
+                if config.while_.as_ref().is_some_and(|re| !re.is_match(text)) {
+                    break;
+                }
+                if config.until.as_ref().is_some_and(|re| re.is_match(text)) {
+                    if config.until_inclusive {
+                        out.write_all(&line)?;
                     }
+                    break;
+                }
+                out.write_all(&line)?;
+            }
+        }
+        (
+            Count::AllButLast(num_lines),
+            Input::File { mut file, start },
+            Separator::Byte(delimiter),
+        ) => {
+            let end = all_but_last_lines_offset(&mut file, start, num_lines, *delimiter)?;
+            copy_range(file, start, end, out)?;
+        }
+        (Count::AllButLast(num_lines), input, separator) => {
+            print_all_but_last_lines(input.into_reader(), num_lines, separator, out)?;
+        }
+        (Count::Percent(_), ..) => unreachable!("resolved above"),
+    }
+    Ok(())
+}
+
+fn print_bytes(input: Input, count: Count, out: &mut impl Write) -> MyResult<()> {
+    let (input, count) = resolve_percent(input, count, None)?;
+    match (count, input) {
+        (Count::First(num_bytes), input) => {
+            io::copy(&mut input.into_reader().take(num_bytes as u64), out)?;
+        }
+        (Count::AllButLast(num_bytes), Input::File { file, start }) => {
+            let end = file.metadata()?.len().saturating_sub(num_bytes as u64);
+            copy_range(file, start, end, out)?;
+        }
+        (Count::AllButLast(num_bytes), input) => {
+            print_all_but_last_bytes(input.into_reader(), num_bytes, out)?;
+        }
+        (Count::Percent(_), _) => unreachable!("resolved above"),
+    }
+    Ok(())
+}
+
+// Reads the next character into `buf`, or the next invalid UTF-8 sequence
+// which counts as one. Bytes are only consumed from `reader` once they are
+// known to belong to it, though telling where an invalid sequence ends takes
+// one byte of lookahead. Returns false at the end of input.
+fn read_char(reader: &mut impl BufRead, buf: &mut Vec<u8>) -> io::Result<bool> {
+    buf.clear();
+    loop {
+        let next = match reader.fill_buf()?.first() {
+            Some(&byte) => byte,
+            None => return Ok(!buf.is_empty()),
+        };
+        buf.push(next);
+        match std::str::from_utf8(buf) {
+            // still an incomplete sequence
+            Err(e) if e.error_len().is_none() => reader.consume(1),
+            // `next` starts something else
+            Err(_) if buf.len() > 1 => {
+                buf.pop();
+                return Ok(true);
+            }
+            // a whole character or a lone invalid byte
+            _ => {
+                reader.consume(1);
+                return Ok(true);
+            }
+        }
+    }
+}
+
+fn extends_grapheme(cluster: &[u8], next: &[u8]) -> bool {
+    match (std::str::from_utf8(cluster), std::str::from_utf8(next)) {
+        (Ok(cluster), Ok(next)) => format!("{}{}", cluster, next).graphemes(true).count() == 1,
+        _ => false,
+    }
+}
+
+// Prints the first `count` Unicode scalar values, or grapheme clusters.
+// Input is read a character at a time so nothing after the output is
+// consumed, except for the one character it takes to see that a grapheme
+// cluster has ended (and see read_char on invalid UTF-8).
+fn print_text_units(
+    mut reader: impl BufRead,
+    count: usize,
+    graphemes: bool,
+    out: &mut impl Write,
+) -> MyResult<()> {
+    let mut remaining = count;
+    let mut unit = Vec::new();
+    let mut next = Vec::new();
+    while remaining > 0 && read_char(&mut reader, &mut next)? {
+        if !graphemes {
+            out.write_all(&next)?;
+            remaining -= 1;
+            continue;
+        }
+        if !unit.is_empty() && !extends_grapheme(&unit, &next) {
+            out.write_all(&unit)?;
+            remaining -= 1;
+            unit.clear();
+        }
+        unit.extend_from_slice(&next);
+    }
+    if remaining > 0 {
+        out.write_all(&unit)?;
+    }
+    Ok(())
+}
+
+// Field delimiters --table tries when none is given, the most frequent one
+// in the header row wins
+const TABLE_DELIMITERS: [u8; 4] = [b',', b'\t', b';', b'|'];
+// columns are narrowed down to this before any is dropped
+const TABLE_MIN_WIDTH: usize = 4;
+const TABLE_GAP: &str = "  ";
+
+// "\t" or "tab" for a tab, otherwise a single byte; empty means detect it
+fn parse_table_delimiter(value: &str) -> Result<Option<u8>, String> {
+    match value {
+        "" => Ok(None),
+        "\\t" | "tab" => Ok(Some(b'\t')),
+        _ => parse_delimiter(value).map(Some),
+    }
+}
+
+fn table_width() -> usize {
+    if let Some((terminal_size::Width(width), _)) = terminal_size::terminal_size() {
+        return width as usize;
+    }
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .filter(|&width| width > 0)
+        .unwrap_or(80)
+}
+
+// Reads the header row, which may span lines if a quoted field has a newline
+fn read_header_row(reader: &mut impl BufRead) -> io::Result<Vec<u8>> {
+    let mut row = Vec::new();
+    while reader.read_until(b'\n', &mut row)? > 0 {
+        if row.iter().filter(|&&b| b == b'"').count() % 2 == 0 {
+            break;
+        }
+    }
+    Ok(row)
+}
+
+fn detect_delimiter(header: &[u8]) -> u8 {
+    let mut counts = [0; TABLE_DELIMITERS.len()];
+    let mut quoted = false;
+    for &b in header {
+        if b == b'"' {
+            quoted = !quoted;
+        } else if let Some(i) = TABLE_DELIMITERS.iter().position(|&d| d == b && !quoted) {
+            counts[i] += 1;
+        }
+    }
+    (0..counts.len())
+        .rev()
+        .max_by_key(|&i| counts[i])
+        .filter(|&i| counts[i] > 0)
+        .map_or(b',', |i| TABLE_DELIMITERS[i])
+}
+
+fn display_field(field: &[u8]) -> String {
+    String::from_utf8_lossy(field)
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t")
+}
+
+// Cuts `text` down to `width` columns, ending it with "…" when it was cut
+fn fit_cell(text: &str, width: usize) -> String {
+    if text.width() <= width {
+        return text.to_string();
+    }
+    let mut cell = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w >= width {
+            break;
+        }
+        cell.push(c);
+        used += w;
+    }
+    cell.push('…');
+    cell
+}
+
+// Narrows the widest columns until the table fits in `max_width`, then
+// drops columns from the right; returns whether any were dropped
+fn fit_columns(widths: &mut Vec<usize>, max_width: usize) -> bool {
+    let total = |widths: &[usize]| {
+        widths.iter().sum::<usize>() + TABLE_GAP.len() * widths.len().saturating_sub(1)
+    };
+    while total(widths) > max_width {
+        let (i, &widest) = widths
+            .iter()
+            .enumerate()
+            .max_by_key(|&(i, &w)| (w, std::cmp::Reverse(i)))
+            .expect("no columns");
+        if widest <= TABLE_MIN_WIDTH {
+            break;
+        }
+        widths[i] -= 1;
+    }
+    if total(widths) <= max_width {
+        return false;
+    }
+    // leave room for the "…" column
+    while widths.len() > 1 && total(widths) + TABLE_GAP.len() + 1 > max_width {
+        widths.pop();
+    }
+    true
+}
+
+// Prints the header row and `num_rows` rows of CSV/TSV as aligned columns.
+// Returns how many bytes of input the rows took, which is less than was
+// read when the reader buffers ahead.
+fn print_table(
+    mut reader: impl BufRead,
+    num_rows: usize,
+    delimiter: Option<u8>,
+    out: &mut impl Write,
+) -> MyResult<u64> {
+    let header = read_header_row(&mut reader)?;
+    if header.is_empty() {
+        return Ok(0);
+    }
+    let delimiter = delimiter.unwrap_or_else(|| detect_delimiter(&header));
+    let mut csv = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(delimiter)
+        .from_reader(io::Cursor::new(header).chain(reader));
+
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let mut record = csv::ByteRecord::new();
+    while rows.len() <= num_rows && csv.read_byte_record(&mut record)? {
+        rows.push(record.iter().map(display_field).collect());
+    }
+    let consumed = csv.position().byte();
+
+    let num_columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let mut widths = vec![0; num_columns];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.width());
+        }
+    }
+    let dropped = fit_columns(&mut widths, table_width());
+
+    let mut print_row = |cells: Vec<String>| -> io::Result<()> {
+        let mut line = cells.join(TABLE_GAP);
+        if dropped {
+            line.push_str(TABLE_GAP);
+            line.push('…');
+        }
+        writeln!(out, "{}", line.trim_end())
+    };
+    let pad = |text: &str, width: usize| {
+        let cell = fit_cell(text, width);
+        let padding = width.saturating_sub(cell.width());
+        cell + &" ".repeat(padding)
+    };
+    for (row_num, row) in rows.iter().enumerate() {
+        let cells = widths
+            .iter()
+            .enumerate()
+            .map(|(i, &width)| pad(row.get(i).map_or("", String::as_str), width))
+            .collect();
+        print_row(cells)?;
+        if row_num == 0 {
+            print_row(widths.iter().map(|&width| "-".repeat(width)).collect())?;
+        }
+    }
+    Ok(consumed)
+}
+
+// Polls a regular file until it has the `config.skip` plus the requested
+// whole lines, or bytes with -c, after `start`. Returns false if
+// `config.timeout` ran out first.
+fn wait_for_input(file: &File, start: u64, config: &Config) -> MyResult<bool> {
+    let needed = match (config.bytes, config.lines) {
+        (Some(Count::First(count)), _) | (None, Count::First(count)) => {
+            config.skip.saturating_add(count)
+        }
+        _ => return Ok(true),
+    };
+    let deadline = config.timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let available = if config.bytes.is_some() {
+            file.metadata()?.len().saturating_sub(start)
+        } else {
+            let mut reader = BufReader::new(file);
+            reader.seek(SeekFrom::Start(start))?;
+            let mut records = Records::new(reader, &config.separator);
+            let mut line = Vec::new();
+            let mut lines = 0;
+            while lines < needed as u64 {
+                match records.next(&mut line)? {
+                    Some(len) if len < line.len() => lines += 1,
+                    _ => break,
                 }
             }
+            let (mut reader, _) = records.into_parts();
+            reader.seek(SeekFrom::Start(start))?;
+            lines
+        };
+        if available >= needed as u64 {
+            return Ok(true);
+        }
+        let now = Instant::now();
+        match deadline {
+            Some(deadline) if now >= deadline => return Ok(false),
+            Some(deadline) => thread::sleep(POLL_INTERVAL.min(deadline - now)),
+            None => thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+// Drops the first `config.skip` lines, or bytes with -c. Byte skips on a
+// regular file are a seek, and a regular file stays one so that -n -K and
+// -c -K can still seek from the new start.
+fn skip_input(input: Input, config: &Config) -> MyResult<Input> {
+    if config.skip == 0 {
+        return Ok(input);
+    }
+    let skip = config.skip as u64;
+    match (config.bytes.is_some(), input) {
+        (true, Input::File { mut file, start }) => {
+            let start = (start + skip).min(file.metadata()?.len().max(start));
+            file.seek(SeekFrom::Start(start))?;
+            Ok(Input::File { file, start })
+        }
+        (true, Input::Stream(mut reader)) => {
+            io::copy(&mut reader.by_ref().take(skip), &mut io::sink())?;
+            Ok(Input::Stream(reader))
+        }
+        (false, Input::File { file, .. }) => {
+            let mut records = Records::new(BufReader::new(file), &config.separator);
+            skip_records(&mut records, config.skip)?;
+            let (mut reader, pending) = records.into_parts();
+            let start = reader.stream_position()? - pending.len() as u64;
+            let mut file = reader.into_inner();
+            file.seek(SeekFrom::Start(start))?;
+            Ok(Input::File { file, start })
+        }
+        (false, Input::Stream(reader)) => {
+            let mut records = Records::new(reader, &config.separator);
+            skip_records(&mut records, config.skip)?;
+            let (reader, pending) = records.into_parts();
+            Ok(Input::Stream(Box::new(
+                io::Cursor::new(pending).chain(reader),
+            )))
+        }
+    }
+}
+
+fn skip_records(records: &mut Records<impl BufRead>, count: usize) -> io::Result<()> {
+    let mut line = Vec::new();
+    for _ in 0..count {
+        if records.next(&mut line)?.is_none() {
+            break;
         }
     }
     Ok(())
 }
+
+// an error's message without io::Error's " (os error N)"
+fn describe(e: &dyn Error) -> String {
+    let message = e.to_string();
+    match message.rsplit_once(" (os error ") {
+        Some((description, _)) => description.to_string(),
+        None => message,
+    }
+}
+
+// Heads one file into `out`, after its header if there is one. Problems
+// with the file are reported on `err` and it is skipped, only failing to
+// write the output is an error. `headers_written` says whether an earlier
+// header needs an empty line before this one, and is set once it's printed.
+fn head_file(
+    filename: &str,
+    headers_written: &mut bool,
+    config: &Config,
+    out: &mut impl Write,
+    err: &mut impl Write,
+) -> MyResult<Outcome> {
+    if config.json {
+        return json_file(filename, config, out);
+    }
+    let input = match open(filename, config.exact) {
+        Ok(input) => input,
+        Err(e) => {
+            // what earlier files printed goes first
+            out.flush()?;
+            writeln!(
+                err,
+                "headr: cannot open '{}' for reading: {}",
+                filename,
+                describe(&*e)
+            )?;
+            return Ok(Outcome::Failed);
+        }
+    };
+    let mut out = CountingWriter {
+        inner: out,
+        count: 0,
+        failed: false,
+    };
+    match print_file(input, filename, headers_written, config, &mut out) {
+        Ok(Printed { complete: true, .. }) => Ok(Outcome::Done),
+        Ok(Printed {
+            complete: false, ..
+        }) => {
+            writeln!(err, "headr: timed out waiting for '{}'", filename)?;
+            Ok(Outcome::TimedOut)
+        }
+        Err(e) if out.failed => Err(From::from(format!(
+            "error writing 'standard output': {}",
+            describe(&*e)
+        ))),
+        Err(e) => {
+            out.flush()?;
+            writeln!(
+                err,
+                "headr: error reading '{}': {}",
+                filename,
+                describe(&*e)
+            )?;
+            Ok(Outcome::Failed)
+        }
+    }
+}
+
+// what print_file made of an input
+struct Printed {
+    // false if --timeout ran out waiting for it
+    complete: bool,
+    // whether the input went on after what was printed; only known for
+    // regular files and, with --json, for streams, unless --exact keeps us
+    // from reading ahead to find out
+    truncated: Option<bool>,
+}
+
+// A stream that outlives the reader the printing takes, so that it can be
+// checked for more afterwards; `taken` is how much that reader read.
+struct TrackedStream {
+    inner: Rc<RefCell<Box<dyn BufRead>>>,
+    taken: Rc<Cell<u64>>,
+}
+
+impl Read for TrackedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.borrow_mut().read(buf)?;
+        self.taken.set(self.taken.get() + read as u64);
+        Ok(read)
+    }
+}
+
+fn print_file(
+    input: Input,
+    filename: &str,
+    headers_written: &mut bool,
+    config: &Config,
+    out: &mut CountingWriter<impl Write>,
+) -> MyResult<Printed> {
+    let input = if config.decompress {
+        decompress(input)?
+    } else {
+        input
+    };
+    let mut complete = true;
+    if let (true, Input::File { file, start }) = (config.wait, &input) {
+        complete = wait_for_input(file, *start, config)?;
+    }
+    let input = skip_input(input, config)?;
+    let (input, tracked) = match input {
+        Input::Stream(reader) if config.json => {
+            let inner = Rc::new(RefCell::new(reader));
+            let taken = Rc::new(Cell::new(0));
+            let stream = TrackedStream {
+                inner: Rc::clone(&inner),
+                taken: Rc::clone(&taken),
+            };
+            let capacity = if config.exact { 1 } else { BLOCK_SIZE };
+            let reader = BufReader::with_capacity(capacity, stream);
+            (Input::Stream(Box::new(reader)), Some((inner, taken)))
+        }
+        input => (input, None),
+    };
+    if config.print_headers {
+        writeln!(
+            out,
+            "{}{}",
+            if *headers_written { "\n" } else { "" },
+            format_header(&config.header_format, filename)
+        )?;
+        *headers_written = true;
+    }
+
+    // leave a seekable input right after what we printed, so
+    // `{ headr -n 1; catr; } < file` works
+    let rewind = match &input {
+        Input::File { file, start } => Some((file.try_clone()?, *start)),
+        Input::Stream(_) => None,
+    };
+    let header_len = out.count;
+    let mut consumed = None;
+    if let (Some(delimiter), Count::First(num_rows)) = (config.table, config.lines) {
+        consumed = Some(print_table(input.into_reader(), num_rows, delimiter, out)?);
+    } else if let Some(num_bytes) = config.bytes {
+        print_bytes(input, num_bytes, out)?;
+    } else if let Some(num_chars) = config.chars {
+        print_text_units(input.into_reader(), num_chars, false, out)?;
+    } else if let Some(num_graphemes) = config.graphemes {
+        print_text_units(input.into_reader(), num_graphemes, true, out)?;
+    } else {
+        print_lines(input, config, out)?;
+    }
+    let printed = out.count - header_len;
+    let mut truncated = Some(false);
+    if let Some((mut file, start)) = rewind {
+        let end = start + consumed.unwrap_or(printed);
+        truncated = Some(end < file.metadata()?.len());
+        file.seek(SeekFrom::Start(end))?;
+    } else if let Some((inner, taken)) = tracked {
+        truncated = if printed < taken.get() {
+            Some(true)
+        } else if config.exact {
+            None
+        } else {
+            Some(!inner.borrow_mut().fill_buf()?.is_empty())
+        };
+    }
+    Ok(Printed {
+        complete,
+        truncated,
+    })
+}
+
+// --json: a line with an object for the file, its lines (or base64 bytes
+// with -c) and how many, or just its name and an error
+fn json_file(filename: &str, config: &Config, out: &mut impl Write) -> MyResult<Outcome> {
+    let mut buf = CountingWriter {
+        inner: Vec::new(),
+        count: 0,
+        failed: false,
+    };
+    let printed = match open(filename, config.exact) {
+        Err(e) => Err(format!(
+            "cannot open '{}' for reading: {}",
+            filename,
+            describe(&*e)
+        )),
+        Ok(input) => print_file(input, filename, &mut false, config, &mut buf)
+            .map_err(|e| format!("error reading '{}': {}", filename, describe(&*e))),
+    };
+
+    let mut object = json!({ "name": filename });
+    let outcome = match printed {
+        Err(message) => {
+            object["error"] = Value::from(message);
+            Outcome::Failed
+        }
+        Ok(printed) => {
+            if config.bytes.is_some() {
+                object["bytes"] = Value::from(BASE64.encode(&buf.inner));
+                object["byte_count"] = Value::from(buf.count);
+            } else {
+                let mut records = Records::new(buf.inner.as_slice(), &config.separator);
+                let mut lines = Vec::new();
+                let mut line = Vec::new();
+                while let Some(len) = records.next(&mut line)? {
+                    lines.push(String::from_utf8_lossy(&line[..len]).into_owned());
+                }
+                let line_count = lines.len();
+                object["lines"] = Value::from(lines);
+                object["line_count"] = Value::from(line_count);
+            }
+            object["truncated"] = Value::from(printed.truncated);
+            if config.wait {
+                object["timed_out"] = Value::from(!printed.complete);
+            }
+            object["error"] = Value::Null;
+            if printed.complete {
+                Outcome::Done
+            } else {
+                Outcome::TimedOut
+            }
+        }
+    };
+    serde_json::to_writer(&mut *out, &object)?;
+    writeln!(out)?;
+    Ok(outcome)
+}
+
+// how many files per job may be started ahead of the output
+const JOBS_AHEAD: usize = 2;
+
+// Holds workers back from starting a file that is `size` or more files
+// ahead of the next one to print, so output that is done but waits for a
+// slow file before it stays bounded by the window instead of growing with
+// the whole input. `printed` is None once printing stopped.
+struct Window {
+    printed: Mutex<Option<usize>>,
+    moved: Condvar,
+    size: usize,
+}
+
+impl Window {
+    fn new(size: usize) -> Self {
+        Window {
+            printed: Mutex::new(Some(0)),
+            moved: Condvar::new(),
+            size,
+        }
+    }
+
+    // false if printing stopped, and the file should not be started
+    fn wait_for(&self, file_num: usize) -> bool {
+        let mut printed = self.printed.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            match *printed {
+                None => return false,
+                Some(n) if file_num < n + self.size => return true,
+                _ => printed = self.moved.wait(printed).unwrap_or_else(|e| e.into_inner()),
+            }
+        }
+    }
+
+    fn advance(&self, printed: Option<usize>) {
+        *self.printed.lock().unwrap_or_else(|e| e.into_inner()) = printed;
+        self.moved.notify_all();
+    }
+}
+
+// Heads the files on `config.jobs` threads, each file into a buffer of its
+// own, and prints the buffers in argument order as they are ready.
+fn head_files_in_parallel(config: &Config) -> MyResult<Vec<Outcome>> {
+    let next = AtomicUsize::new(0);
+    let window = Window::new(config.jobs * JOBS_AHEAD);
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..config.jobs.min(config.files.len()) {
+            let tx = tx.clone();
+            let (next, window) = (&next, &window);
+            scope.spawn(move || loop {
+                let file_num = next.fetch_add(1, Ordering::Relaxed);
+                let Some(filename) = config.files.get(file_num) else {
+                    break;
+                };
+                if !window.wait_for(file_num) {
+                    break;
+                }
+                // earlier files aren't known yet, the empty line before the
+                // header is left to print_in_order
+                let (mut out, mut err, mut header) = (Vec::new(), Vec::new(), false);
+                let outcome = head_file(filename, &mut header, config, &mut out, &mut err)
+                    .map_err(|e| e.to_string());
+                if tx.send((file_num, (out, err, header, outcome))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        let print_in_order = || -> MyResult<Vec<Outcome>> {
+            let mut ready = BTreeMap::new();
+            let mut outcomes = Vec::new();
+            let mut headers_written = false;
+            for file_num in 0..config.files.len() {
+                let (out, err, header, outcome) = loop {
+                    if let Some(done) = ready.remove(&file_num) {
+                        break done;
+                    }
+                    let (done_num, done) = rx.recv()?;
+                    ready.insert(done_num, done);
+                };
+                let mut stdout = io::stdout().lock();
+                if header && headers_written {
+                    stdout.write_all(b"\n")?;
+                }
+                headers_written |= header;
+                stdout.write_all(&out)?;
+                stdout.flush()?;
+                io::stderr().write_all(&err)?;
+                outcomes.push(outcome?);
+                window.advance(Some(file_num + 1));
+            }
+            Ok(outcomes)
+        };
+        let result = print_in_order();
+        // don't start on any more files after an error
+        window.advance(None);
+        result
+    })
+}
+
+// Returns the exit status: 1 if any file could not be read, otherwise
+// TIMEOUT_STATUS if --timeout ran out on any
+pub fn run(config: Config) -> MyResult<i32> {
+    let outcomes = if config.jobs > 1 {
+        head_files_in_parallel(&config)?
+    } else {
+        let mut outcomes = Vec::new();
+        let mut headers_written = false;
+        for filename in &config.files {
+            let mut out = io::stdout().lock();
+            outcomes.push(head_file(
+                filename,
+                &mut headers_written,
+                &config,
+                &mut out,
+                &mut io::stderr(),
+            )?);
+        }
+        outcomes
+    };
+    if outcomes.contains(&Outcome::Failed) {
+        Ok(1)
+    } else if outcomes.contains(&Outcome::TimedOut) {
+        Ok(TIMEOUT_STATUS)
+    } else {
+        Ok(0)
+    }
+}
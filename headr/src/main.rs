@@ -1,6 +1,9 @@
 fn main() {
-    if let Err(e) = headr::get_args().and_then(headr::run) {
-        eprintln!("{}", e);
-        std::process::exit(1);
+    match headr::get_args().and_then(headr::run) {
+        Ok(status) => std::process::exit(status),
+        Err(e) => {
+            eprintln!("headr: {}", e);
+            std::process::exit(1);
+        }
     }
 }
@@ -1,10 +1,12 @@
-use assert_cmd::{assert, Command};
+use assert_cmd::{assert::OutputAssertExt, Command};
 use predicates::prelude::*;
 use rand::{distributions::Alphanumeric, Rng};
 use std::{
     error::Error,
     fs::{self, File},
-    io::Read,
+    io::{Read, Seek, SeekFrom, Write},
+    thread,
+    time::Duration,
 };
 
 type TestResult = Result<(), Box<dyn Error>>;
@@ -33,11 +35,11 @@ fn gen_bad_file() -> String {
     }
 }
 
-fn dies_bad(args: &str, fmt_string: &str) -> TestResult {
+fn dies_bad(args: &str, fmt_string: &str, kind: &str) -> TestResult {
     let bad = random_string();
     let expected = format!(
-        "error: invalid value '{}' {}: invalid digit found in string",
-        &bad, &fmt_string
+        "error: invalid value '{}' {}: Illegal {} count -- {}",
+        &bad, &fmt_string, &kind, &bad
     );
     Command::cargo_bin(PRG)?
         .args([args, &bad, EMPTY])
@@ -51,7 +53,7 @@ fn dies_bad(args: &str, fmt_string: &str) -> TestResult {
 fn dies_bad_bytes() -> TestResult {
     let fmt_string = "for '--bytes <bytes>'";
     let args = "-c";
-    dies_bad(args, fmt_string)?;
+    dies_bad(args, fmt_string, "byte")?;
     Ok(())
 }
 
@@ -59,7 +61,7 @@ fn dies_bad_bytes() -> TestResult {
 fn dies_bad_lines() -> TestResult {
     let fmt_string = "for '--lines <lines>'";
     let args = "-n";
-    dies_bad(args, fmt_string)?;
+    dies_bad(args, fmt_string, "line")?;
     Ok(())
 }
 
@@ -78,38 +80,71 @@ fn dies_bytes_and_lines() -> TestResult {
 #[test]
 fn skips_bad_file() -> TestResult {
     let bad = gen_bad_file();
-    let expected = format!("{}: .* [(]os error 2[)]", bad);
+    let expected = format!(
+        "headr: cannot open '{}' for reading: No such file or directory\n",
+        bad
+    );
     Command::cargo_bin(PRG)?
         .args([EMPTY, &bad, ONE])
         .assert()
-        .stderr(predicate::str::is_match(expected)?);
+        .code(1)
+        .stdout(predicate::str::contains("Öne line, four words."))
+        .stderr(expected);
+    Ok(())
+}
+
+#[test]
+fn bad_first_file_no_empty_line() -> TestResult {
+    let bad = gen_bad_file();
+    let expected = format!(
+        "==> {} <==\nÖne line, four words.\n\n==> {} <==\n{}",
+        ONE,
+        TWO,
+        fs::read_to_string(TWO)?
+    );
+    for jobs in ["1", "3"] {
+        Command::cargo_bin(PRG)?
+            .args(["-j", jobs, &bad, ONE, &bad, TWO])
+            .assert()
+            .code(1)
+            .stdout(expected.clone());
+    }
+    Ok(())
+}
+
+#[test]
+fn reports_directory() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["tests/inputs", ONE])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("Öne line, four words."))
+        .stderr("headr: error reading 'tests/inputs': Is a directory\n");
     Ok(())
 }
 
 fn run(args: &[&str], expected_file: &str) -> TestResult {
     let mut file = File::open(expected_file)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-    let expected = String::from_utf8_lossy(&buffer);
+    let mut expected = Vec::new();
+    file.read_to_end(&mut expected)?;
     Command::cargo_bin(PRG)?
         .args(args)
         .assert()
         .success()
-        .stdout(predicate::eq(expected.as_bytes() as &[u8]));
+        .stdout(predicate::eq(expected));
     Ok(())
 }
 
 fn run_stdin(args: &[&str], input_file: &str, expected_file: &str) -> TestResult {
     let mut file = File::open(expected_file)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-    let expected = String::from_utf8_lossy(&buffer);
-    let input = fs::read_to_string(input_file)?;
+    let mut expected = Vec::new();
+    file.read_to_end(&mut expected)?;
+    let input = fs::read(input_file)?;
     Command::cargo_bin(PRG)?
         .write_stdin(input)
         .args(args)
         .assert()
-        .stdout(predicate::eq(expected.as_bytes() as &[u8]));
+        .stdout(predicate::eq(expected));
     Ok(())
 }
 
@@ -402,3 +437,1054 @@ fn multiple_files_c4() -> TestResult {
         "tests/expected/all.c4.out",
     )
 }
+
+// --------------------------------------------------
+// negative counts, expected output generated with GNU head
+
+// --------------------------------------------------
+#[test]
+fn empty_n_minus2() -> TestResult {
+    run(&[EMPTY, "-n", "-2"], "tests/expected/empty.txt.n-2.out")
+}
+
+#[test]
+fn empty_n_minus4() -> TestResult {
+    run(&[EMPTY, "-n", "-4"], "tests/expected/empty.txt.n-4.out")
+}
+
+#[test]
+fn empty_c_minus2() -> TestResult {
+    run(&[EMPTY, "-c", "-2"], "tests/expected/empty.txt.c-2.out")
+}
+
+#[test]
+fn empty_c_minus4() -> TestResult {
+    run(&[EMPTY, "-c", "-4"], "tests/expected/empty.txt.c-4.out")
+}
+
+// --------------------------------------------------
+#[test]
+fn one_n_minus2() -> TestResult {
+    run(&[ONE, "-n", "-2"], "tests/expected/one.txt.n-2.out")
+}
+
+#[test]
+fn one_n_minus4() -> TestResult {
+    run(&[ONE, "-n", "-4"], "tests/expected/one.txt.n-4.out")
+}
+
+#[test]
+fn one_c_minus2() -> TestResult {
+    run(&[ONE, "-c", "-2"], "tests/expected/one.txt.c-2.out")
+}
+
+#[test]
+fn one_c_minus4() -> TestResult {
+    run(&[ONE, "-c", "-4"], "tests/expected/one.txt.c-4.out")
+}
+
+// --------------------------------------------------
+#[test]
+fn two_n_minus2() -> TestResult {
+    run(&[TWO, "-n", "-2"], "tests/expected/two.txt.n-2.out")
+}
+
+#[test]
+fn two_n_minus4() -> TestResult {
+    run(&[TWO, "-n", "-4"], "tests/expected/two.txt.n-4.out")
+}
+
+#[test]
+fn two_c_minus2() -> TestResult {
+    run(&[TWO, "-c", "-2"], "tests/expected/two.txt.c-2.out")
+}
+
+#[test]
+fn two_c_minus4() -> TestResult {
+    run(&[TWO, "-c", "-4"], "tests/expected/two.txt.c-4.out")
+}
+
+// --------------------------------------------------
+#[test]
+fn three_n_minus2() -> TestResult {
+    run(&[THREE, "-n", "-2"], "tests/expected/three.txt.n-2.out")
+}
+
+#[test]
+fn three_n_minus4() -> TestResult {
+    run(&[THREE, "-n", "-4"], "tests/expected/three.txt.n-4.out")
+}
+
+#[test]
+fn three_c_minus2() -> TestResult {
+    run(&[THREE, "-c", "-2"], "tests/expected/three.txt.c-2.out")
+}
+
+#[test]
+fn three_c_minus4() -> TestResult {
+    run(&[THREE, "-c", "-4"], "tests/expected/three.txt.c-4.out")
+}
+
+// --------------------------------------------------
+#[test]
+fn ten_n_minus2() -> TestResult {
+    run(&[TEN, "-n", "-2"], "tests/expected/ten.txt.n-2.out")
+}
+
+#[test]
+fn ten_n_minus4() -> TestResult {
+    run(&[TEN, "-n", "-4"], "tests/expected/ten.txt.n-4.out")
+}
+
+#[test]
+fn ten_c_minus2() -> TestResult {
+    run(&[TEN, "-c", "-2"], "tests/expected/ten.txt.c-2.out")
+}
+
+#[test]
+fn ten_c_minus4() -> TestResult {
+    run(&[TEN, "-c", "-4"], "tests/expected/ten.txt.c-4.out")
+}
+
+#[test]
+fn ten_n_minus2_stdin() -> TestResult {
+    run_stdin(&["-n", "-2"], TEN, "tests/expected/ten.txt.n-2.out")
+}
+
+#[test]
+fn ten_n_minus4_stdin() -> TestResult {
+    run_stdin(&["-n", "-4"], TEN, "tests/expected/ten.txt.n-4.out")
+}
+
+#[test]
+fn ten_c_minus2_stdin() -> TestResult {
+    run_stdin(&["-c", "-2"], TEN, "tests/expected/ten.txt.c-2.out")
+}
+
+#[test]
+fn ten_c_minus4_stdin() -> TestResult {
+    run_stdin(&["-c", "-4"], TEN, "tests/expected/ten.txt.c-4.out")
+}
+
+// --------------------------------------------------
+#[test]
+fn multiple_files_n_minus2() -> TestResult {
+    run(
+        &[EMPTY, ONE, TWO, THREE, TEN, "-n", "-2"],
+        "tests/expected/all.n-2.out",
+    )
+}
+
+#[test]
+fn multiple_files_c_minus4() -> TestResult {
+    run(
+        &["-c", "-4", EMPTY, ONE, TWO, THREE, TEN],
+        "tests/expected/all.c-4.out",
+    )
+}
+
+#[test]
+fn ten_n_minus0() -> TestResult {
+    run(&[TEN, "-n", "-0"], "tests/inputs/ten.txt")
+}
+
+// --------------------------------------------------
+// size suffixes
+fn run_stdin_len(args: &[&str], input: &str, expected_len: usize) -> TestResult {
+    Command::cargo_bin(PRG)?
+        .write_stdin(input)
+        .args(args)
+        .assert()
+        .success()
+        .stdout(predicate::eq(&input.as_bytes()[..expected_len]));
+    Ok(())
+}
+
+#[test]
+fn bytes_suffixes() -> TestResult {
+    let input = "x".repeat(3000);
+    for (size, expected_len) in [
+        ("1b", 512),
+        ("2kB", 2000),
+        ("1KB", 1000),
+        ("1k", 1024),
+        ("2K", 2048),
+        ("1KiB", 1024),
+        ("1M", 3000),
+        ("1MiB", 3000),
+        ("-1KB", 2000),
+        ("-2b", 1976),
+    ] {
+        run_stdin_len(&["-c", size], &input, expected_len)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn lines_suffix() -> TestResult {
+    run(&[TEN, "-n", "2k"], "tests/expected/ten.txt.out")
+}
+
+#[test]
+fn dies_bad_suffix() -> TestResult {
+    for (args, msg) in [
+        (["-c", "4X"], "Illegal byte count -- 4X"),
+        (["-c", "4KX"], "Illegal byte count -- 4KX"),
+        (["-n", "K"], "Illegal line count -- K"),
+        (["-n", "-4g"], "Illegal line count -- -4g"),
+    ] {
+        Command::cargo_bin(PRG)?
+            .args(args)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(msg));
+    }
+    Ok(())
+}
+
+#[test]
+fn dies_too_large() -> TestResult {
+    for size in ["99999999999999999999", "99999E", "1Q"] {
+        let msg = format!("Illegal byte count -- {}: value too large", size);
+        Command::cargo_bin(PRG)?
+            .args(["-c", size])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(msg));
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+// header control
+#[test]
+fn ten_verbose_n2() -> TestResult {
+    run(&["-v", TEN, "-n", "2"], "tests/expected/ten.txt.v.n2.out")
+}
+
+#[test]
+fn multiple_files_quiet_n2() -> TestResult {
+    run(
+        &["-q", EMPTY, ONE, TWO, THREE, TEN, "-n", "2"],
+        "tests/expected/all.q.n2.out",
+    )
+}
+
+#[test]
+fn last_of_quiet_and_verbose_wins() -> TestResult {
+    run(
+        &["-q", "--verbose", TEN, "-n", "2"],
+        "tests/expected/ten.txt.v.n2.out",
+    )?;
+    run(
+        &["-v", "--quiet", TEN, "-n", "2"],
+        "tests/expected/ten.txt.n2.out",
+    )
+}
+
+#[test]
+fn header_format() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args([
+            "-n",
+            "1",
+            "--header-format",
+            "# {name} {size} {x}",
+            ONE,
+            TEN,
+        ])
+        .assert()
+        .success()
+        .stdout(format!(
+            "# {} 23 {{x}}\nÖne line, four words.\n\n# {} 49 {{x}}\none\n",
+            ONE, TEN
+        ));
+    Ok(())
+}
+
+#[test]
+fn header_format_stdin_mtime() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-v", "--header-format", "{name} {size} {mtime}", "-"])
+        .write_stdin("hello\n")
+        .assert()
+        .success()
+        .stdout("- - -\nhello\n");
+    Command::cargo_bin(PRG)?
+        .args(["-v", "--header-format", "{mtime}", TEN])
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match("^[0-9]+\none\n")?);
+    Ok(())
+}
+
+// --------------------------------------------------
+// raw bytes, characters and graphemes
+#[test]
+fn bytes_are_raw() -> TestResult {
+    let input: Vec<u8> = vec![0xff, 0x00, 0xc3, 0x96, 0x80, b'\n'];
+    Command::cargo_bin(PRG)?
+        .args(["-c", "5"])
+        .write_stdin(input.clone())
+        .assert()
+        .success()
+        .stdout(predicate::eq(&input[..5]));
+    Ok(())
+}
+
+#[test]
+fn one_chars() -> TestResult {
+    for (count, expected) in [("1", "Ö"), ("4", "Öne "), ("30", "Öne line, four words.\n")] {
+        Command::cargo_bin(PRG)?
+            .args(["--chars", count, ONE])
+            .assert()
+            .success()
+            .stdout(expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn chars_across_lines_and_invalid_utf8() -> TestResult {
+    let input: Vec<u8> = [&b"a\xffb\n"[..], "日本\n".as_bytes()].concat();
+    for (count, expected_len) in [(2, 2), (4, 4), (5, 7), (7, input.len())] {
+        Command::cargo_bin(PRG)?
+            .args(["--chars", &count.to_string()])
+            .write_stdin(input.clone())
+            .assert()
+            .success()
+            .stdout(predicate::eq(&input[..expected_len]));
+    }
+    Ok(())
+}
+
+#[test]
+fn graphemes() -> TestResult {
+    // "e" + combining acute accent, and a flag made of two regional indicators
+    let input = "e\u{301}\u{1F1EF}\u{1F1F5}x\r\ny\n";
+    for (count, expected) in [
+        ("1", "e\u{301}"),
+        ("2", "e\u{301}\u{1F1EF}\u{1F1F5}"),
+        ("4", "e\u{301}\u{1F1EF}\u{1F1F5}x\r\n"),
+        ("6", input),
+    ] {
+        Command::cargo_bin(PRG)?
+            .args(["--graphemes", count])
+            .write_stdin(input)
+            .assert()
+            .success()
+            .stdout(expected);
+    }
+    Command::cargo_bin(PRG)?
+        .args(["--chars", "2"])
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout("e\u{301}");
+    Ok(())
+}
+
+#[test]
+fn dies_chars_and_bytes() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--chars", "1", "-c", "2"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+    Command::cargo_bin(PRG)?
+        .args(["--graphemes", "0"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Illegal grapheme count -- 0"));
+    Ok(())
+}
+
+// --------------------------------------------------
+// leaving stdin right after the printed data
+fn run_shared_stdin(args: &[&str], expected: &str, expected_offset: u64) -> TestResult {
+    let mut file = File::open(TEN)?;
+    let mut cmd = std::process::Command::new(assert_cmd::cargo::cargo_bin(PRG));
+    cmd.args(args).stdin(file.try_clone()?);
+    cmd.output()?
+        .assert()
+        .success()
+        .stdout(expected.to_string());
+    assert_eq!(file.stream_position()?, expected_offset);
+    Ok(())
+}
+
+#[test]
+fn seekable_stdin_offset() -> TestResult {
+    run_shared_stdin(&["-n", "2"], "one\ntwo\n", 8)?;
+    run_shared_stdin(&["-c", "6"], "one\ntw", 6)?;
+    run_shared_stdin(&["--chars", "2"], "on", 2)?;
+    run_shared_stdin(&["-n", "-8"], "one\ntwo\n", 8)
+}
+
+#[test]
+fn seekable_stdin_resumes() -> TestResult {
+    let mut file = File::open(TEN)?;
+    file.seek(SeekFrom::Start(8))?;
+    let mut cmd = std::process::Command::new(assert_cmd::cargo::cargo_bin(PRG));
+    cmd.args(["-n", "-6"]).stdin(file.try_clone()?);
+    cmd.output()?.assert().success().stdout("three\nfour\n");
+    assert_eq!(file.stream_position()?, 19);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn exact_pipe() -> TestResult {
+    let headr = assert_cmd::cargo::cargo_bin(PRG);
+    let script = format!(
+        "{0} --exact -n 2; {0} --exact -c 3; {0} --exact --chars 1; cat",
+        headr.display()
+    );
+    Command::new("sh")
+        .args(["-c", &script])
+        .write_stdin(fs::read(TEN)?)
+        .assert()
+        .success()
+        .stdout(fs::read_to_string(TEN)?);
+    Ok(())
+}
+
+// the line --until stops at can't be put back into a pipe
+#[cfg(unix)]
+#[test]
+fn exact_pipe_until() -> TestResult {
+    let headr = assert_cmd::cargo::cargo_bin(PRG);
+    let script = format!("{} --exact --until ^c; cat", headr.display());
+    Command::new("sh")
+        .args(["-c", &script])
+        .write_stdin("a\nb\nc\nd\n")
+        .assert()
+        .success()
+        .stdout("a\nb\nd\n");
+    Ok(())
+}
+
+// the character that ends the last cluster can't be put back either
+#[cfg(unix)]
+#[test]
+fn exact_pipe_graphemes() -> TestResult {
+    let headr = assert_cmd::cargo::cargo_bin(PRG);
+    let script = format!("{} --exact --graphemes 1; cat", headr.display());
+    Command::new("sh")
+        .args(["-c", &script])
+        .write_stdin("abc")
+        .assert()
+        .success()
+        .stdout("ac");
+    Ok(())
+}
+
+// --------------------------------------------------
+// NUL-terminated records, expected output generated with GNU head -z
+const ZERO: &str = "./tests/inputs/zero.txt";
+
+#[test]
+fn zero_n2() -> TestResult {
+    run(&["-z", ZERO, "-n", "2"], "tests/expected/zero.txt.z.n2.out")
+}
+
+#[test]
+fn zero_n4() -> TestResult {
+    run(
+        &["--zero-terminated", ZERO, "-n", "4"],
+        "tests/expected/zero.txt.z.n4.out",
+    )
+}
+
+#[test]
+fn zero_n_minus2() -> TestResult {
+    run(
+        &["-z", ZERO, "-n", "-2"],
+        "tests/expected/zero.txt.z.n-2.out",
+    )
+}
+
+#[test]
+fn zero_n_minus4() -> TestResult {
+    run(
+        &["-z", ZERO, "-n", "-4"],
+        "tests/expected/zero.txt.z.n-4.out",
+    )
+}
+
+#[test]
+fn zero_n2_stdin() -> TestResult {
+    run_stdin(&["-z", "-n", "2"], ZERO, "tests/expected/zero.txt.z.n2.out")
+}
+
+#[test]
+fn zero_n_minus2_stdin() -> TestResult {
+    run_stdin(
+        &["-z", "-n", "-2"],
+        ZERO,
+        "tests/expected/zero.txt.z.n-2.out",
+    )
+}
+
+#[test]
+fn zero_multiple_files_n2() -> TestResult {
+    run(
+        &["-z", "-n", "2", ZERO, TEN],
+        "tests/expected/zero-ten.z.n2.out",
+    )
+}
+
+// --------------------------------------------------
+// custom delimiters and regex-bounded output
+fn run_stdin_str(args: &[&str], input: &str, expected: &str) -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(args)
+        .write_stdin(input.to_string())
+        .assert()
+        .success()
+        .stdout(expected.to_string());
+    Ok(())
+}
+
+#[test]
+fn delimiter() -> TestResult {
+    run_stdin_str(&["-d", ";", "-n", "2"], "a;b\nc;d", "a;b\nc;")?;
+    run_stdin_str(&["-d", ";", "-n", "-1"], "a;b;c;d", "a;b;c;")?;
+    run_stdin_str(&["--delimiter", ";", "-n", "-1"], "a;b;c;d;", "a;b;c;")
+}
+
+#[test]
+fn dies_bad_delimiter() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-d", "ab"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "the delimiter must be a single byte",
+        ));
+    Ok(())
+}
+
+#[test]
+fn record_separator() -> TestResult {
+    let input = "a, b,c ,  d";
+    run_stdin_str(
+        &["--record-separator", " *, *", "-n", "3"],
+        input,
+        "a, b,c ,  ",
+    )?;
+    run_stdin_str(
+        &["--record-separator", " *, *", "-n", "-1"],
+        input,
+        "a, b,c ,  ",
+    )?;
+    run_stdin_str(
+        &["--record-separator", "-+", "-n", "2"],
+        "a----b-c--d",
+        "a----b-",
+    )?;
+    run(
+        &["--record-separator", "\r?\n", "-n", "2", THREE],
+        "tests/expected/three.txt.n2.out",
+    )
+}
+
+#[test]
+fn dies_record_separator_with_exact() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--exact", "--record-separator", ";", "-n", "1"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+    Ok(())
+}
+
+#[test]
+fn until() -> TestResult {
+    run(&["--until", "^f", TEN], "tests/expected/ten.txt.until.out")?;
+    run(
+        &["--until", "^f", "--inclusive", TEN],
+        "tests/expected/ten.txt.until.inclusive.out",
+    )?;
+    run(
+        &["--until", "^f", "-n", "2", TEN],
+        "tests/expected/ten.txt.n2.out",
+    )?;
+    run(&["--until", "^never$", TEN], TEN)
+}
+
+#[test]
+fn while_matches() -> TestResult {
+    run(&["--while", "o", TEN], "tests/expected/ten.txt.n2.out")?;
+    run_stdin_str(&["--while", "^#", "-z"], "#a\0#b\0c\0#d\0", "#a\0#b\0")
+}
+
+#[test]
+fn dies_until_with_negative_lines() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--until", "x", "-n", "-1", TEN])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--until and --while cannot be used with a negative line count",
+        ));
+    Ok(())
+}
+
+// --------------------------------------------------
+// skipping and ranges
+#[test]
+fn skip_lines() -> TestResult {
+    run(&["--skip", "8", TEN], "tests/expected/ten.txt.skip8.out")?;
+    run(
+        &["--skip", "8", "-n", "-1", TEN],
+        "tests/expected/ten.txt.skip8.n-1.out",
+    )?;
+    run_stdin(
+        &["--skip", "8", "-n", "-1"],
+        TEN,
+        "tests/expected/ten.txt.skip8.n-1.out",
+    )?;
+    run(&["--skip", "20", TEN], "tests/expected/empty.txt.out")
+}
+
+#[test]
+fn skip_bytes() -> TestResult {
+    run_stdin_str(&["--skip", "4", "-c", "3"], "one\ntwo\n", "two")?;
+    run_stdin_str(&["--skip", "1K", "-c", "2"], &"x".repeat(1024), "")?;
+    run(
+        &["--skip", "40", "-c", "-4", TEN],
+        "tests/expected/ten.txt.skip40.c-4.out",
+    )
+}
+
+#[test]
+fn range() -> TestResult {
+    run(
+        &["--range", "3:5", TEN],
+        "tests/expected/ten.txt.range3-5.out",
+    )?;
+    run_stdin(
+        &["--range", "3:5"],
+        TEN,
+        "tests/expected/ten.txt.range3-5.out",
+    )?;
+    run(&["--range", ":2", TEN], "tests/expected/ten.txt.n2.out")?;
+    run(&["--range", "9:", TEN], "tests/expected/ten.txt.skip8.out")
+}
+
+#[test]
+fn byte_range() -> TestResult {
+    run_stdin_str(&["--byte-range", "5:7"], "one\ntwo\n", "two")?;
+    run(
+        &["--byte-range", "41:", TEN],
+        "tests/expected/ten.txt.skip40.out",
+    )?;
+    run_stdin(
+        &["--byte-range", "41:"],
+        TEN,
+        "tests/expected/ten.txt.skip40.out",
+    )
+}
+
+#[test]
+fn dies_bad_range() -> TestResult {
+    for range in ["5:3", "0:3", "3", "a:b"] {
+        Command::cargo_bin(PRG)?
+            .args(["--range", range])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(format!(
+                "Illegal range -- {}",
+                range
+            )));
+    }
+    Ok(())
+}
+
+#[test]
+fn skip_leaves_seekable_stdin_after_output() -> TestResult {
+    run_shared_stdin(&["--skip", "2", "-n", "1"], "three\n", 14)?;
+    run_shared_stdin(&["--byte-range", "5:7"], "two", 7)
+}
+
+// --------------------------------------------------
+// percentages, rounded up
+#[test]
+fn percent_lines() -> TestResult {
+    run(&["-p", "15", TEN], "tests/expected/ten.txt.n2.out")?;
+    run(&["-n", "40%", TEN], "tests/expected/ten.txt.n4.out")?;
+    run(&["-p", "100%", TEN], "tests/expected/ten.txt.out")?;
+    run(&["-p", "50", THREE], "tests/expected/three.txt.n2.out")?;
+    run_stdin(&["-p", "40%"], TEN, "tests/expected/ten.txt.n4.out")?;
+    run_stdin_str(&["-p", "0.5"], "a\nb\n", "a\n")
+}
+
+#[test]
+fn percent_bytes() -> TestResult {
+    run(&["-c", "10%", TEN], "tests/expected/ten.txt.c5.out")?;
+    run_stdin(&["-c", "10%"], TEN, "tests/expected/ten.txt.c5.out")?;
+    run(&["-c", "50%", EMPTY], "tests/expected/empty.txt.out")
+}
+
+#[test]
+fn percent_leaves_seekable_stdin_after_output() -> TestResult {
+    run_shared_stdin(&["-p", "20"], "one\ntwo\n", 8)
+}
+
+#[test]
+fn dies_bad_percent() -> TestResult {
+    for bad in ["0%", "101%", "-5%", "1.23456%", "x%", "%"] {
+        Command::cargo_bin(PRG)?
+            .args(["-n", bad])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(format!(
+                "Illegal line count -- {}",
+                bad
+            )));
+    }
+    Command::cargo_bin(PRG)?
+        .args(["-p", "10", "-c", "5"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// --------------------------------------------------
+// CSV/TSV tables, laid out for a terminal COLUMNS wide
+const TABLE_CSV: &str = "./tests/inputs/table.csv";
+const TABLE_TSV: &str = "./tests/inputs/table.tsv";
+
+fn run_table(args: &[&str], columns: &str, expected_file: &str) -> TestResult {
+    let expected = fs::read(expected_file)?;
+    let output = Command::cargo_bin(PRG)?
+        .env("COLUMNS", columns)
+        .args(args)
+        .output()
+        .expect("fail");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected);
+    Ok(())
+}
+
+#[test]
+fn table() -> TestResult {
+    run_table(
+        &["--table", TABLE_CSV],
+        "80",
+        "tests/expected/table.csv.out",
+    )
+}
+
+#[test]
+fn table_quoted_newline_is_one_row() -> TestResult {
+    run_table(
+        &["--table", "-n", "2", TABLE_CSV],
+        "80",
+        "tests/expected/table.csv.n2.out",
+    )
+}
+
+#[test]
+fn table_truncates_to_width() -> TestResult {
+    run_table(
+        &["--table", TABLE_CSV],
+        "20",
+        "tests/expected/table.csv.w20.out",
+    )?;
+    run_table(
+        &["--table", TABLE_CSV],
+        "14",
+        "tests/expected/table.csv.w14.out",
+    )
+}
+
+#[test]
+fn table_delimiter() -> TestResult {
+    run_table(
+        &["--table", "-n", "1", TABLE_TSV],
+        "80",
+        "tests/expected/table.tsv.n1.out",
+    )?;
+    run_table(
+        &["--table=\\t", "-n", "1", TABLE_TSV],
+        "80",
+        "tests/expected/table.tsv.n1.out",
+    )
+}
+
+#[test]
+fn table_leaves_seekable_stdin_after_rows() -> TestResult {
+    let mut file = File::open(TABLE_CSV)?;
+    let output = std::process::Command::new(assert_cmd::cargo::cargo_bin(PRG))
+        .args(["--table", "-n", "1"])
+        .env("COLUMNS", "80")
+        .stdin(file.try_clone()?)
+        .output()?;
+    assert!(output.status.success());
+    let mut rest = String::new();
+    file.read_to_string(&mut rest)?;
+    assert!(rest.starts_with("bob,\"New\nYork\""));
+    Ok(())
+}
+
+#[test]
+fn dies_table_with_negative_lines() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--table", "-n", "-2", TABLE_CSV])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--table cannot be used with a negative line count",
+        ));
+    Ok(())
+}
+
+#[test]
+fn dies_table_with_exact() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--table", "--exact", "-n", "1"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+    Ok(())
+}
+
+// --------------------------------------------------
+// waiting for a file that is still being written
+fn growing_file(initial: &str, later: &'static str) -> Result<String, Box<dyn Error>> {
+    let path = std::env::temp_dir().join(format!("headr-{}", random_string()));
+    fs::write(&path, initial)?;
+    let filename = path.to_string_lossy().into_owned();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(later.as_bytes()).unwrap();
+    });
+    Ok(filename)
+}
+
+#[test]
+fn wait_for_lines() -> TestResult {
+    let filename = growing_file("one\n", "two\nthree\nfour\n")?;
+    Command::cargo_bin(PRG)?
+        .args(["--wait", "-n", "3", &filename])
+        .assert()
+        .success()
+        .stdout("one\ntwo\nthree\n");
+    fs::remove_file(filename)?;
+    Ok(())
+}
+
+#[test]
+fn wait_for_bytes() -> TestResult {
+    let filename = growing_file("on", "e\ntwo\n")?;
+    Command::cargo_bin(PRG)?
+        .args(["--wait", "-c", "5", &filename])
+        .assert()
+        .success()
+        .stdout("one\nt");
+    fs::remove_file(filename)?;
+    Ok(())
+}
+
+#[test]
+fn wait_timeout() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--wait", "--timeout", "100ms", "-n", "4", THREE])
+        .assert()
+        .code(124)
+        .stdout(fs::read_to_string("tests/expected/three.txt.n4.out")?)
+        .stderr(predicate::str::contains(format!(
+            "headr: timed out waiting for '{}'",
+            THREE
+        )));
+    Ok(())
+}
+
+#[test]
+fn dies_wait_without_count() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--wait", "--range", "2:", TEN])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--wait needs a positive line or byte count",
+        ));
+    Ok(())
+}
+
+// --------------------------------------------------
+// reading files in parallel
+#[test]
+fn jobs() -> TestResult {
+    run(
+        &["-j", "3", EMPTY, ONE, TWO, THREE, TEN],
+        "tests/expected/all.out",
+    )?;
+    run(
+        &["--jobs", "8", "-n", "-2", EMPTY, ONE, TWO, THREE, TEN],
+        "tests/expected/all.n-2.out",
+    )
+}
+
+#[test]
+fn jobs_more_files_than_window() -> TestResult {
+    let files = [ONE, TWO, THREE, TEN].repeat(10);
+    let expected = Command::cargo_bin(PRG)?
+        .args(["-n", "3"])
+        .args(&files)
+        .output()?;
+    Command::cargo_bin(PRG)?
+        .args(["-n", "3", "-j", "2"])
+        .args(&files)
+        .assert()
+        .success()
+        .stdout(predicate::eq(expected.stdout.as_slice()));
+    Ok(())
+}
+
+// stdout and stderr into one file, to see how they interleave
+fn combined_output(args: &[&str]) -> Result<(Option<i32>, String), Box<dyn Error>> {
+    let path = std::env::temp_dir().join(format!("headr-{}", random_string()));
+    let file = File::create(&path)?;
+    let status = std::process::Command::new(assert_cmd::cargo::cargo_bin(PRG))
+        .args(args)
+        .stdout(file.try_clone()?)
+        .stderr(file)
+        .status()?;
+    let output = fs::read_to_string(&path)?;
+    fs::remove_file(path)?;
+    Ok((status.code(), output))
+}
+
+#[test]
+fn jobs_keep_errors_in_place() -> TestResult {
+    let bad = gen_bad_file();
+    let files = [ONE, bad.as_str(), TWO, THREE, bad.as_str(), TEN];
+    // -c 3 leaves the output of ONE without a newline before the error
+    for (count, head) in [
+        (["-n", "1"], "Öne line, four words.\n"),
+        (["-c", "3"], "Ön"),
+    ] {
+        let sequential = combined_output(&[&count[..], &files[..]].concat())?;
+        let parallel = combined_output(&[&count[..], &["-j", "4"], &files[..]].concat())?;
+        assert_eq!(sequential.0, Some(1));
+        assert!(sequential.1.contains(&format!(
+            "==> {} <==\n{}headr: cannot open '{}'",
+            ONE, head, bad
+        )));
+        assert_eq!(parallel, sequential);
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+// compressed input
+#[test]
+fn decompress() -> TestResult {
+    for ext in ["gz", "bz2", "xz", "zst"] {
+        let compressed = format!("{}.{}", TEN, ext);
+        run(
+            &["--decompress", "-n", "2", &compressed],
+            "tests/expected/ten.txt.n2.out",
+        )?;
+        run_stdin(
+            &["--decompress", "-c", "-4"],
+            &compressed,
+            "tests/expected/ten.txt.c-4.out",
+        )?;
+    }
+    run(
+        &["--decompress", "-n", "2", TEN],
+        "tests/expected/ten.txt.n2.out",
+    )
+}
+
+#[test]
+fn dies_decompress_with_exact() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["--decompress", "--exact", "-c", "2"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+    Ok(())
+}
+
+#[test]
+fn decompress_stops_early() -> TestResult {
+    // cut off after 2000 bytes, gzip -d fails on it
+    Command::cargo_bin(PRG)?
+        .args(["--decompress", "-n", "2", "tests/inputs/truncated.log.gz"])
+        .assert()
+        .success()
+        .stdout("a line of a long log\na line of a long log\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+// JSON output
+#[test]
+fn json_lines() -> TestResult {
+    run(
+        &["--json", "-n", "2", EMPTY, ONE, TWO, THREE, TEN],
+        "tests/expected/all.json.n2.out",
+    )
+}
+
+#[test]
+fn json_bytes() -> TestResult {
+    run(
+        &["--json", "-c", "4", ONE, TEN],
+        "tests/expected/all.json.c4.out",
+    )
+}
+
+#[test]
+fn json_stdin_truncated() -> TestResult {
+    run_stdin_str(
+        &["--json", "-n", "1"],
+        "a\nb\n",
+        "{\"name\":\"-\",\"lines\":[\"a\"],\"line_count\":1,\"truncated\":true,\"error\":null}\n",
+    )?;
+    run_stdin_str(
+        &["--json", "-n", "2"],
+        "a\nb\n",
+        "{\"name\":\"-\",\"lines\":[\"a\",\"b\"],\"line_count\":2,\"truncated\":false,\"error\":null}\n",
+    )
+}
+
+// finding out whether there is more would take a byte from the pipe
+#[cfg(unix)]
+#[test]
+fn json_exact_pipe() -> TestResult {
+    let headr = assert_cmd::cargo::cargo_bin(PRG);
+    let script = format!("{} --exact --json -n 1; cat", headr.display());
+    Command::new("sh")
+        .args(["-c", &script])
+        .write_stdin("a\nb\nc\n")
+        .assert()
+        .success()
+        .stdout(
+            "{\"name\":\"-\",\"lines\":[\"a\"],\"line_count\":1,\"truncated\":null,\"error\":null}\nb\nc\n",
+        );
+    Ok(())
+}
+
+#[test]
+fn json_error() -> TestResult {
+    let bad = gen_bad_file();
+    let expected = format!(
+        "{{\"name\":\"{}\",\"error\":\"cannot open '{}' for reading: \
+        No such file or directory\"}}\n",
+        bad, bad
+    );
+    Command::cargo_bin(PRG)?
+        .args(["--json", &bad])
+        .assert()
+        .code(1)
+        .stdout(expected)
+        .stderr("");
+    Ok(())
+}
@@ -1,14 +1,24 @@
-use clap::Parser;
+use clap::{ArgGroup, Parser, ValueEnum};
+use csv_core::ReadFieldResult;
+use regex::bytes::Regex;
 use std::{
+    borrow::Cow,
+    cell::RefCell,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
     error::Error,
     fs::File,
-    io::{self, BufRead, BufReader, Write},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
 };
+use unicase::UniCase;
 
 type MyResult<T> = Result<T, Box<dyn Error>>;
 
 #[derive(Parser)]
 #[command(author("Yacob (Kobi) Cohen-Arazi <kobi.cohenarazi@gmail.com>"), version("0.1.0"), about("Rust uniq app"), long_about = None)]
+#[command(group(ArgGroup::new("anywhere").multiple(true).args(["global", "sort_by_count", "top"])))]
+#[command(group(ArgGroup::new("key").args(["key_regex", "key_field"])))]
 struct Cli {
     #[arg(value_name = "Input file", default_value("-"))]
     in_file: String,
@@ -16,6 +26,172 @@ struct Cli {
     out_file: Option<String>,
     #[arg(short('c'), long("count"), help("Show counts"))]
     count: bool,
+    #[arg(
+        short('d'),
+        long("repeated"),
+        help("Only print duplicate lines, one for each group")
+    )]
+    repeated: bool,
+    #[arg(short('u'), long("unique"), help("Only print unique lines"))]
+    unique: bool,
+    #[arg(
+        short('D'),
+        long("all-repeated"),
+        value_name("METHOD"),
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("none"),
+        conflicts_with("count"),
+        help("Print all duplicate lines, groups can be delimited with an empty line")
+    )]
+    all_repeated: Option<Delimit>,
+    #[arg(
+        long("group"),
+        value_name("METHOD"),
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("separate"),
+        conflicts_with_all(["count", "repeated", "unique", "all_repeated"]),
+        help("Show all lines, separating groups with an empty line")
+    )]
+    group: Option<Grouping>,
+    #[arg(
+        short('f'),
+        long("skip-fields"),
+        value_name("N"),
+        default_value("0"),
+        help("Avoid comparing the first N fields")
+    )]
+    skip_fields: usize,
+    #[arg(
+        short('s'),
+        long("skip-chars"),
+        value_name("N"),
+        default_value("0"),
+        help("Avoid comparing the first N characters")
+    )]
+    skip_chars: usize,
+    #[arg(
+        short('w'),
+        long("check-chars"),
+        value_name("N"),
+        help("Compare no more than N characters in lines")
+    )]
+    check_chars: Option<usize>,
+    #[arg(
+        short('i'),
+        long("ignore-case"),
+        help("Ignore differences in case when comparing")
+    )]
+    ignore_case: bool,
+    #[arg(
+        long("ignore-trailing-space"),
+        help("Ignore whitespace at the end of lines when comparing")
+    )]
+    ignore_trailing_space: bool,
+    #[arg(
+        long("ignore-whitespace-changes"),
+        help("Ignore changes in the amount of whitespace when comparing")
+    )]
+    ignore_whitespace_changes: bool,
+    #[arg(
+        long("global"),
+        conflicts_with_all(["all_repeated", "group"]),
+        help("Remove repeated lines anywhere in the input, not only adjacent ones")
+    )]
+    global: bool,
+    #[arg(
+        long("max-memory"),
+        value_name("SIZE"),
+        value_parser(parse_size),
+        requires("anywhere"),
+        help("With --global, --sort-by-count or --top, keep at most SIZE of lines in memory (K, M, G suffixes)")
+    )]
+    max_memory: Option<usize>,
+    #[arg(
+        long("sort-by-count"),
+        value_name("ORDER"),
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("desc"),
+        conflicts_with_all(["all_repeated", "group"]),
+        help("Count lines anywhere in the input and print the most frequent first")
+    )]
+    sort_by_count: Option<Order>,
+    #[arg(
+        long("top"),
+        value_name("N"),
+        conflicts_with_all(["all_repeated", "group"]),
+        help("Print only the first N lines by count, most frequent unless --sort-by-count=asc")
+    )]
+    top: Option<usize>,
+    #[arg(
+        long("key-regex"),
+        value_name("REGEX"),
+        value_parser(|value: &str| Regex::new(value).map_err(|e| e.to_string())),
+        help("Compare only the first capture group of REGEX, or all of the match without one")
+    )]
+    key_regex: Option<Regex>,
+    #[arg(
+        long("key-field"),
+        value_name("N"),
+        value_parser(parse_field),
+        help("Compare only field N of delimited lines, quoted as in CSV")
+    )]
+    key_field: Option<usize>,
+    #[arg(
+        long("delimiter"),
+        value_name("CHAR"),
+        default_value(","),
+        value_parser(parse_delimiter),
+        requires("key_field"),
+        help("What separates the fields for --key-field")
+    )]
+    delimiter: u8,
+    #[arg(
+        long("drop-missing-key"),
+        requires("key"),
+        help("Leave out lines without a key instead of comparing all of them")
+    )]
+    drop_missing_key: bool,
+}
+
+// how --sort-by-count orders lines, those with the same count stay in the
+// order they first came
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+// what --key-regex or --key-field takes from a line to compare
+#[derive(Debug)]
+enum KeySource {
+    Regex(Regex),
+    // `index` counts from 0, the reader is set up once and reset per line
+    Field {
+        index: usize,
+        reader: Box<RefCell<csv_core::Reader>>,
+    },
+}
+
+// where -D puts an empty line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Delimit {
+    None,
+    // before each group
+    Prepend,
+    // between groups
+    Separate,
+}
+
+// where --group puts an empty line, there is always one between groups
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Grouping {
+    Separate,
+    Prepend,
+    Append,
+    Both,
 }
 
 #[derive(Debug)]
@@ -23,6 +199,52 @@ pub struct Config {
     in_file: String,
     out_file: Option<String>,
     count: bool,
+    repeated: bool,
+    unique: bool,
+    all_repeated: Option<Delimit>,
+    group: Option<Grouping>,
+    skip_fields: usize,
+    skip_chars: usize,
+    check_chars: Option<usize>,
+    ignore_case: bool,
+    ignore_trailing_space: bool,
+    ignore_whitespace_changes: bool,
+    global: bool,
+    max_memory: Option<usize>,
+    sort_by_count: Option<Order>,
+    top: Option<usize>,
+    key: Option<KeySource>,
+    drop_missing_key: bool,
+}
+
+// Which lines get printed, as GNU uniq decides it: the only line of a
+// group of one and the first line of a bigger group, or with -D every line
+// of it, each but the last as the next one matches it and the last when
+// the group ends.
+impl Config {
+    fn output_unique(&self) -> bool {
+        !self.repeated && self.all_repeated.is_none()
+    }
+
+    fn output_first_repeated(&self) -> bool {
+        !self.unique
+    }
+
+    fn output_later_repeated(&self) -> bool {
+        self.all_repeated.is_some()
+    }
+
+    // `count` is the size of the group so far, `matched` is set when the
+    // line is printed because the next one repeats it (-D)
+    fn selects(&self, count: u64, matched: bool) -> bool {
+        if matched {
+            self.output_later_repeated()
+        } else if count == 1 {
+            self.output_unique()
+        } else {
+            count > 1 && self.output_first_repeated()
+        }
+    }
 }
 
 pub fn get_args() -> MyResult<Config> {
@@ -31,27 +253,215 @@ pub fn get_args() -> MyResult<Config> {
         in_file: cli.in_file,
         out_file: cli.out_file,
         count: cli.count,
+        repeated: cli.repeated,
+        unique: cli.unique,
+        all_repeated: cli.all_repeated,
+        group: cli.group,
+        skip_fields: cli.skip_fields,
+        skip_chars: cli.skip_chars,
+        check_chars: cli.check_chars,
+        ignore_case: cli.ignore_case,
+        ignore_trailing_space: cli.ignore_trailing_space,
+        ignore_whitespace_changes: cli.ignore_whitespace_changes,
+        global: cli.global || cli.sort_by_count.is_some() || cli.top.is_some(),
+        max_memory: cli.max_memory,
+        sort_by_count: cli.sort_by_count.or(cli.top.map(|_| Order::Desc)),
+        top: cli.top,
+        key: match (cli.key_regex, cli.key_field) {
+            (Some(regex), _) => Some(KeySource::Regex(regex)),
+            (_, Some(field)) => Some(KeySource::Field {
+                index: field - 1,
+                reader: Box::new(RefCell::new(
+                    csv_core::ReaderBuilder::new()
+                        .delimiter(cli.delimiter)
+                        .build(),
+                )),
+            }),
+            _ => None,
+        },
+        drop_missing_key: cli.drop_missing_key,
     };
     //println!("{:?}", cfg);
     Ok(cfg)
 }
 
-fn print_out(line: &str, out: &mut Box<dyn Write>) -> MyResult<()> {
-    write!(out, "{}", line).map_err(|e| format!("{}", e).into())
+// a number of bytes, optionally with a K, M, G or T suffix (powers of 1024)
+fn parse_size(value: &str) -> Result<usize, String> {
+    let (digits, shift) = match value.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => {
+            let shift = match unit.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("invalid size unit '{}'", unit)),
+            };
+            (&value[..i], shift)
+        }
+        _ => (value, 0),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("invalid size '{}'", value))
+}
+
+// fields count from 1
+fn parse_field(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("invalid field number '{}'", value))
+}
+
+fn parse_delimiter(value: &str) -> Result<u8, String> {
+    match value.as_bytes() {
+        [delimiter] if delimiter.is_ascii() => Ok(*delimiter),
+        _ => Err(format!(
+            "the delimiter must be a single ASCII character, not '{}'",
+            value
+        )),
+    }
+}
+
+// a line as read, with or without its newline
+fn content(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\n").unwrap_or(line)
+}
+
+// every line printed gets a newline, the last one too
+fn print_out(line: &[u8], out: &mut Box<dyn Write>) -> MyResult<()> {
+    out.write_all(content(line))
+        .and_then(|_| out.write_all(b"\n"))
+        .map_err(|e| format!("{}", e).into())
+}
+
+fn print_out_with_count(line: &[u8], count: u64, out: &mut Box<dyn Write>) -> MyResult<()> {
+    write!(out, "{:>4} ", count).map_err(|e| format!("{}", e))?;
+    print_out(line, out)
 }
 
-fn print_out_with_count(line: &str, count: u64, out: &mut Box<dyn Write>) -> MyResult<()> {
-    write!(out, "{:>4} {}", count, line).map_err(|e| format!("{}", e).into())
+// Chars are counted as UTF-8 sequences, each byte of invalid UTF-8 on its
+// own; this is where the `n`th one starts, or None if there are fewer.
+fn char_offset(bytes: &[u8], n: usize) -> Option<usize> {
+    let is_char_start = |b: &u8| !(0x80..0xc0).contains(b);
+    let mut starts = (0..bytes.len()).filter(|&i| is_char_start(&bytes[i]));
+    starts.nth(n)
+}
+
+// What --key-regex or --key-field picks out of a line, None when it isn't
+// there; without them the whole line. Quotes around a field are taken off,
+// but a field can't go on past the end of the line.
+fn extract_key<'a>(line: &'a [u8], config: &Config) -> Option<Cow<'a, [u8]>> {
+    match &config.key {
+        None => Some(Cow::Borrowed(line)),
+        Some(KeySource::Regex(regex)) => {
+            let captures = regex.captures(line)?;
+            let group = if captures.len() > 1 { 1 } else { 0 };
+            captures.get(group).map(|key| Cow::Borrowed(key.as_bytes()))
+        }
+        Some(KeySource::Field { index, reader }) => {
+            nth_field(&mut reader.borrow_mut(), line, *index).map(Cow::Owned)
+        }
+    }
+}
+
+// field `index` of a line as CSV has it, unquoted
+fn nth_field(reader: &mut csv_core::Reader, mut line: &[u8], index: usize) -> Option<Vec<u8>> {
+    reader.reset();
+    // unquoting only makes a field shorter than the line
+    let mut field = vec![0; line.len().max(1)];
+    let (mut current, mut len) = (0, 0);
+    loop {
+        // the line runs out with InputEmpty, reading on with nothing left
+        // ends the last field
+        let (result, read, written) = reader.read_field(line, &mut field[len..]);
+        line = &line[read..];
+        len += written;
+        match result {
+            ReadFieldResult::InputEmpty => {}
+            ReadFieldResult::OutputFull => field.resize(field.len() * 2, 0),
+            ReadFieldResult::Field { .. } if current == index => {
+                field.truncate(len);
+                return Some(field);
+            }
+            ReadFieldResult::Field { record_end: false } => (current, len) = (current + 1, 0),
+            ReadFieldResult::Field { record_end: true } | ReadFieldResult::End => return None,
+        }
+    }
+}
+
+// The part of a key that is compared: after `skip_fields` fields, each
+// blanks then non-blanks as GNU uniq has them, and `skip_chars` more
+// characters, at most `check_chars` characters long. Whitespace at the end
+// goes with --ignore-trailing-space, and with --ignore-whitespace-changes
+// every run of it counts as one space.
+fn compared_part<'a>(mut key: &'a [u8], config: &Config) -> Cow<'a, [u8]> {
+    let is_blank = |b: &u8| *b == b' ' || *b == b'\t';
+    if config.ignore_trailing_space || config.ignore_whitespace_changes {
+        key = key.trim_ascii_end();
+    }
+    for _ in 0..config.skip_fields {
+        let start = key.iter().position(|b| !is_blank(b)).unwrap_or(key.len());
+        key = &key[start..];
+        let end = key.iter().position(is_blank).unwrap_or(key.len());
+        key = &key[end..];
+    }
+    key = char_offset(key, config.skip_chars).map_or(&[], |start| &key[start..]);
+    if let Some(end) = config.check_chars.and_then(|n| char_offset(key, n)) {
+        key = &key[..end];
+    }
+    if !config.ignore_whitespace_changes {
+        return Cow::Borrowed(key);
+    }
+    let mut collapsed = Vec::with_capacity(key.len());
+    for &b in key {
+        if !b.is_ascii_whitespace() {
+            collapsed.push(b);
+        } else if collapsed.last() != Some(&b' ') {
+            collapsed.push(b' ');
+        }
+    }
+    Cow::Owned(collapsed)
+}
+
+// whether the lines with these keys (see `read_line`) belong in the same
+// group, with -i after full Unicode case folding so that "Straße" and
+// "STRASSE" do (only ASCII for lines that aren't UTF-8)
+fn same_key(key: &[u8], other_key: &[u8], config: &Config) -> bool {
+    if !config.ignore_case {
+        return key == other_key;
+    }
+    match (std::str::from_utf8(key), std::str::from_utf8(other_key)) {
+        (Ok(key), Ok(other_key)) => UniCase::unicode(key) == UniCase::unicode(other_key),
+        _ => key.eq_ignore_ascii_case(other_key),
+    }
+}
+
+// What --global tells lines apart by: the key from `read_line`, case
+// folded with -i the same way `same_key` compares
+fn global_key(key: &[u8], config: &Config) -> Vec<u8> {
+    if !config.ignore_case {
+        return key.to_vec();
+    }
+    match std::str::from_utf8(key) {
+        Ok(key) => UniCase::new(key).to_folded_case().into_bytes(),
+        _ => key.to_ascii_lowercase(),
+    }
 }
 
 fn print_with_count(
     count: u64,
-    previous_line: &str,
-    count_cfg: bool,
-    mut out: &mut Box<dyn Write>,
+    matched: bool,
+    previous_line: &[u8],
+    config: &Config,
+    out: &mut Box<dyn Write>,
 ) -> MyResult<()> {
-    if count > 0 {
-        if count_cfg {
+    if config.selects(count, matched) {
+        if config.count {
             print_out_with_count(previous_line, count, out)?
         } else {
             print_out(previous_line, out)?
@@ -69,32 +479,341 @@ fn print_with_count(
 
 pub fn run(config: Config) -> MyResult<()> {
     let mut file = open(&config.in_file).map_err(|e| format!("{}: {}", config.in_file, e))?;
-    let mut current_line = String::new();
-    let mut previous_line = String::new();
-    let mut count: u64 = 0;
     let mut out_file: Box<dyn Write> = match &config.out_file {
         Some(out_name) => Box::new(File::create(out_name)?),
         _ => Box::new(io::stdout()),
     };
+    if let Some(grouping) = config.group {
+        return print_groups(&mut file, grouping, &config, &mut out_file);
+    }
+    if config.global {
+        return print_global(&mut file, &config, &mut out_file);
+    }
+    let mut current_line = Vec::new();
+    let mut previous_line = Vec::new();
+    let (mut current_key, mut previous_key) = (Vec::new(), Vec::new());
+    let mut count: u64 = 0;
+    // with -D=separate, whether a group of repeats has been printed yet
+    let mut first_delimiter = true;
+    loop {
+        let bytes = read_line(&mut file, &mut current_line, &mut current_key, &config)?;
+        if bytes == 0 {
+            break;
+        }
+        let matched = count > 0 && same_key(&current_key, &previous_key, &config);
+        if matched {
+            count += 1;
+            let delimit = match config.all_repeated {
+                Some(Delimit::Prepend) => true,
+                Some(Delimit::Separate) => !first_delimiter,
+                _ => false,
+            };
+            if count == 2 && delimit {
+                writeln!(out_file)?;
+            }
+        } else if count > 1 {
+            first_delimiter = false;
+        }
+        if !matched || config.output_later_repeated() {
+            print_with_count(count, matched, &previous_line, &config, &mut out_file)?;
+            mem::swap(&mut previous_line, &mut current_line);
+            mem::swap(&mut previous_key, &mut current_key);
+            if !matched {
+                count = 1;
+            }
+        }
+        current_line.clear();
+    }
+    print_with_count(count, false, &previous_line, &config, &mut out_file)?;
+    Ok(())
+}
+
+// --group: every line, with empty lines between the groups
+fn print_groups(
+    file: &mut Box<dyn BufRead>,
+    grouping: Grouping,
+    config: &Config,
+    out: &mut Box<dyn Write>,
+) -> MyResult<()> {
+    let mut current_line = Vec::new();
+    let mut current_key = Vec::new();
+    let mut previous_key: Option<Vec<u8>> = None;
     loop {
-        let bytes = file.read_line(&mut current_line)?;
+        let bytes = read_line(file, &mut current_line, &mut current_key, config)?;
         if bytes == 0 {
             break;
         }
-        if current_line.trim_end() != previous_line.trim_end() {
-            print_with_count(count, &previous_line, config.count, &mut out_file);
-            previous_line = current_line.clone();
-            count = 0;
+        match &previous_key {
+            None if matches!(grouping, Grouping::Prepend | Grouping::Both) => writeln!(out)?,
+            Some(previous) if !same_key(&current_key, previous, config) => writeln!(out)?,
+            _ => {}
         }
-        count += 1;
+        print_out(&current_line, out)?;
+        mem::swap(previous_key.get_or_insert_with(Vec::new), &mut current_key);
         current_line.clear();
     }
-    if count > 0 {
-        print_with_count(count, &previous_line, config.count, &mut out_file)?;
+    if previous_key.is_some() && matches!(grouping, Grouping::Append | Grouping::Both) {
+        writeln!(out)?;
     }
     Ok(())
 }
 
+// spill files are merged into one when there are this many of them
+const MAX_SPILL_FILES: usize = 64;
+// what a distinct key costs in memory besides its bytes and its line
+const ENTRY_OVERHEAD: usize = 64;
+
+// A distinct key with the first line that has it, the number of that line
+// and how many lines have the key. `key` is left empty where it isn't
+// needed.
+struct Entry {
+    key: Vec<u8>,
+    first: u64,
+    count: u64,
+    line: Vec<u8>,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        self.key.len() + self.line.len() + ENTRY_OVERHEAD
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        for bytes in [&self.key, &self.line] {
+            out.write_all(&(bytes.len() as u64).to_le_bytes())?;
+            out.write_all(bytes)?;
+        }
+        out.write_all(&self.first.to_le_bytes())?;
+        out.write_all(&self.count.to_le_bytes())
+    }
+
+    // None at the end of the file
+    fn read_from(input: &mut impl Read) -> io::Result<Option<Entry>> {
+        let mut number = [0; 8];
+        match input.read_exact(&mut number) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let mut key = vec![0; u64::from_le_bytes(number) as usize];
+        input.read_exact(&mut key)?;
+        input.read_exact(&mut number)?;
+        let mut line = vec![0; u64::from_le_bytes(number) as usize];
+        input.read_exact(&mut line)?;
+        input.read_exact(&mut number)?;
+        let first = u64::from_le_bytes(number);
+        input.read_exact(&mut number)?;
+        let count = u64::from_le_bytes(number);
+        Ok(Some(Entry {
+            key,
+            first,
+            count,
+            line,
+        }))
+    }
+}
+
+// Entries written out to temporary files, each file sorted by key and then
+// by first line. With `combine` entries with the same key are one.
+struct Spills {
+    files: Vec<File>,
+    combine: bool,
+}
+
+impl Spills {
+    fn new(combine: bool) -> Spills {
+        Spills {
+            files: Vec::new(),
+            combine,
+        }
+    }
+
+    fn spill(&mut self, mut entries: Vec<Entry>) -> MyResult<()> {
+        entries.sort_unstable_by(|a, b| (&a.key, a.first).cmp(&(&b.key, b.first)));
+        let mut out = BufWriter::new(tempfile::tempfile()?);
+        for entry in &entries {
+            entry.write_to(&mut out)?;
+        }
+        self.push(out)?;
+        if self.files.len() >= MAX_SPILL_FILES {
+            let mut out = BufWriter::new(tempfile::tempfile()?);
+            let files = mem::take(&mut self.files);
+            merge(files, self.combine, |entry| Ok(entry.write_to(&mut out)?))?;
+            self.push(out)?;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, out: BufWriter<File>) -> MyResult<()> {
+        let mut file = out.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        self.files.push(file);
+        Ok(())
+    }
+}
+
+// Reads spill files back as one sorted stream; with `combine` the counts of
+// entries with the same key go to the first of them.
+fn merge(
+    files: Vec<File>,
+    combine: bool,
+    mut sink: impl FnMut(Entry) -> MyResult<()>,
+) -> MyResult<()> {
+    let mut readers: Vec<_> = files.into_iter().map(BufReader::new).collect();
+    let mut heads = Vec::with_capacity(readers.len());
+    let mut heap = BinaryHeap::new();
+    for (i, reader) in readers.iter_mut().enumerate() {
+        let head = Entry::read_from(reader)?;
+        if let Some(entry) = &head {
+            heap.push(Reverse((entry.key.clone(), entry.first, i)));
+        }
+        heads.push(head);
+    }
+    let mut pending: Option<Entry> = None;
+    while let Some(Reverse((_, _, i))) = heap.pop() {
+        let entry = heads[i].take().expect("every file in the heap has a head");
+        heads[i] = Entry::read_from(&mut readers[i])?;
+        if let Some(next) = &heads[i] {
+            heap.push(Reverse((next.key.clone(), next.first, i)));
+        }
+        match &mut pending {
+            Some(previous) if combine && previous.key == entry.key => previous.count += entry.count,
+            _ => {
+                if let Some(done) = pending.replace(entry) {
+                    sink(done)?;
+                }
+            }
+        }
+    }
+    pending.map_or(Ok(()), sink)
+}
+
+// --global: the first line with each key, in the order they came or by
+// count. Counts are only known at the end, and past --max-memory the keys
+// go to spill files that are merged by key and then put back in order.
+fn print_global(
+    file: &mut Box<dyn BufRead>,
+    config: &Config,
+    out: &mut Box<dyn Write>,
+) -> MyResult<()> {
+    let (mut line, mut compared) = (Vec::new(), Vec::new());
+    let counted =
+        config.count || config.repeated || config.unique || config.sort_by_count.is_some();
+    if !counted && config.max_memory.is_none() {
+        let mut seen = HashSet::new();
+        while read_line(file, &mut line, &mut compared, config)? > 0 {
+            if seen.insert(global_key(&compared, config)) {
+                print_out(&line, out)?;
+            }
+            line.clear();
+        }
+        return Ok(());
+    }
+    let budget = config.max_memory.unwrap_or(usize::MAX);
+    let mut index: HashMap<Vec<u8>, usize> = HashMap::new();
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut used = 0;
+    let mut by_key = Spills::new(true);
+    let mut first = 0;
+    while read_line(file, &mut line, &mut compared, config)? > 0 {
+        let key = global_key(&compared, config);
+        match index.get(&key) {
+            Some(&i) => entries[i].count += 1,
+            None => {
+                used += key.len() + line.len() + ENTRY_OVERHEAD;
+                index.insert(key, entries.len());
+                entries.push(Entry {
+                    key: Vec::new(),
+                    first,
+                    count: 1,
+                    line: mem::take(&mut line),
+                });
+            }
+        }
+        if used > budget {
+            for (key, i) in index.drain() {
+                entries[i].key = key;
+            }
+            by_key.spill(mem::take(&mut entries))?;
+            used = 0;
+        }
+        first += 1;
+        line.clear();
+    }
+    // with --top, the lines left to print
+    let mut left = config.top.unwrap_or(usize::MAX);
+    let print = |entry: Entry| {
+        if left > 0 && config.selects(entry.count, false) {
+            left -= 1;
+            print_with_count(entry.count, false, &entry.line, config, out)?;
+        }
+        Ok(())
+    };
+    if by_key.files.is_empty() {
+        // stable, so equal counts keep input order
+        entries.sort_by_key(|entry| count_order(entry.count, config));
+        return entries.into_iter().try_for_each(print);
+    }
+    for (key, i) in index.drain() {
+        entries[i].key = key;
+    }
+    by_key.spill(entries)?;
+
+    let mut ordered = Spills::new(false);
+    let mut entries = Vec::new();
+    let mut used = 0;
+    merge(by_key.files, true, |mut entry| {
+        entry.key = count_order(entry.count, config);
+        used += entry.size();
+        entries.push(entry);
+        if used > budget {
+            ordered.spill(mem::take(&mut entries))?;
+            used = 0;
+        }
+        Ok(())
+    })?;
+    ordered.spill(entries)?;
+    merge(ordered.files, false, print)
+}
+
+// What output is sorted by before input order: nothing, or with
+// --sort-by-count the count, as bytes so that spill files sort by it too
+fn count_order(count: u64, config: &Config) -> Vec<u8> {
+    match config.sort_by_count {
+        None => Vec::new(),
+        Some(Order::Asc) => count.to_be_bytes().to_vec(),
+        Some(Order::Desc) => (u64::MAX - count).to_be_bytes().to_vec(),
+    }
+}
+
+// The next line to look at, with --drop-missing-key the next one with a
+// key, and in `key` what of it is compared: the part `compared_part` takes
+// of its key, or of all of it when it has none. Each line's key is only
+// worked out here, once.
+fn read_line(
+    file: &mut Box<dyn BufRead>,
+    line: &mut Vec<u8>,
+    key: &mut Vec<u8>,
+    config: &Config,
+) -> MyResult<usize> {
+    loop {
+        let bytes = file.read_until(b'\n', line)?;
+        if bytes == 0 {
+            return Ok(0);
+        }
+        let extracted = extract_key(content(line), config);
+        if extracted.is_none() && config.drop_missing_key {
+            line.clear();
+            continue;
+        }
+        key.clear();
+        key.extend_from_slice(&compared_part(
+            extracted.as_deref().unwrap_or(content(line)),
+            config,
+        ));
+        return Ok(bytes);
+    }
+}
+
 fn open(filename: &str) -> MyResult<Box<dyn BufRead>> {
     match filename {
         "-" => Ok(Box::new(BufReader::new(io::stdin()))),
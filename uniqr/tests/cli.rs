@@ -74,6 +74,42 @@ const T5: Test = Test {
     out_count: "tests/expected/t5.txt.c.out",
 };
 
+const FIELDS: Test = Test {
+    input: "tests/inputs/fields.txt",
+    out: "tests/expected/fields.txt.out",
+    out_count: "tests/expected/fields.txt.c.out",
+};
+
+const CASE: Test = Test {
+    input: "tests/inputs/case.txt",
+    out: "tests/expected/case.txt.out",
+    out_count: "tests/expected/case.txt.c.out",
+};
+
+const SPACE: Test = Test {
+    input: "tests/inputs/space.txt",
+    out: "tests/expected/space.txt.out",
+    out_count: "tests/expected/space.txt.c.out",
+};
+
+const GLOBAL: Test = Test {
+    input: "tests/inputs/global.txt",
+    out: "tests/expected/global.txt.out",
+    out_count: "tests/expected/global.txt.c.out",
+};
+
+const KEYS: Test = Test {
+    input: "tests/inputs/keys.txt",
+    out: "tests/expected/keys.txt.out",
+    out_count: "tests/expected/keys.txt.c.out",
+};
+
+const CSV: Test = Test {
+    input: "tests/inputs/keys.csv",
+    out: "tests/expected/keys.csv.out",
+    out_count: "tests/expected/keys.csv.c.out",
+};
+
 const T6: Test = Test {
     input: "tests/inputs/t6.txt",
     out: "tests/expected/t6.txt.out",
@@ -124,7 +160,7 @@ fn run(test: &Test) -> TestResult {
 fn run_count(test: &Test) -> TestResult {
     let expected = fs::read_to_string(test.out_count)?;
     Command::cargo_bin(PRG)?
-        .args(&[test.input, "-c"])
+        .args([test.input, "-c"])
         .assert()
         .success()
         .stdout(expected);
@@ -162,12 +198,12 @@ fn run_outfile(test: &Test) -> TestResult {
     let outfile = NamedTempFile::new()?;
     let outpath = &outfile.path().to_str().unwrap();
     Command::cargo_bin(PRG)?
-        .args(&[test.input, outpath])
+        .args([test.input, outpath])
         .assert()
         .success()
         .stdout("");
 
-    let contents = fs::read_to_string(&outpath)?;
+    let contents = fs::read_to_string(outpath)?;
     assert_eq!(&expected, &contents);
 
     Ok(())
@@ -179,13 +215,13 @@ fn run_outfile_count(test: &Test) -> TestResult {
     let outpath = &outfile.path().to_str().unwrap();
 
     Command::cargo_bin(PRG)?
-        .args(&[test.input, outpath, "--count"])
+        .args([test.input, outpath, "--count"])
         .assert()
         .success()
         .stdout("");
 
     let expected = fs::read_to_string(test.out_count)?;
-    let contents = fs::read_to_string(&outpath)?;
+    let contents = fs::read_to_string(outpath)?;
     assert_eq!(&expected, &contents);
 
     Ok(())
@@ -198,13 +234,13 @@ fn run_stdin_outfile_count(test: &Test) -> TestResult {
     let outpath = &outfile.path().to_str().unwrap();
 
     Command::cargo_bin(PRG)?
-        .args(&["-", outpath, "-c"])
+        .args(["-", outpath, "-c"])
         .write_stdin(input)
         .assert()
         .stdout("");
 
     let expected = fs::read_to_string(test.out_count)?;
-    let contents = fs::read_to_string(&outpath)?;
+    let contents = fs::read_to_string(outpath)?;
     assert_eq!(&expected, &contents);
 
     Ok(())
@@ -605,3 +641,357 @@ fn t6_outfile_count() -> TestResult {
 fn t6_stdin_outfile_count() -> TestResult {
     run_stdin_outfile_count(&T6)
 }
+
+// --------------------------------------------------
+// selecting and grouping, expected output checked against GNU uniq
+const SELECTED: [&Test; 7] = [&T1, &T2, &T3, &T4, &T5, &T6, &THREE];
+
+// `mode` goes in the expected file's name, "t1.txt.d.out" for "d"
+fn run_mode(test: &Test, args: &[&str], mode: &str) -> TestResult {
+    let expected_file = test.out.replace(".out", &format!(".{}.out", mode));
+    let expected = fs::read_to_string(expected_file)?;
+    Command::cargo_bin(PRG)?
+        .args(args)
+        .arg(test.input)
+        .assert()
+        .success()
+        .stdout(expected);
+    Ok(())
+}
+
+#[test]
+fn repeated() -> TestResult {
+    for test in SELECTED {
+        run_mode(test, &["-d"], "d")?;
+        run_mode(test, &["--repeated", "-c"], "c.d")?;
+    }
+    Ok(())
+}
+
+#[test]
+fn unique() -> TestResult {
+    for test in SELECTED {
+        run_mode(test, &["-u"], "u")?;
+        run_mode(test, &["--unique", "--count"], "c.u")?;
+    }
+    Ok(())
+}
+
+#[test]
+fn repeated_and_unique_print_nothing() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-d", "-u", THREE.input])
+        .assert()
+        .success()
+        .stdout("");
+    Ok(())
+}
+
+#[test]
+fn all_repeated() -> TestResult {
+    for test in SELECTED {
+        run_mode(test, &["-D"], "D")?;
+        run_mode(test, &["--all-repeated=none"], "D")?;
+    }
+    run_mode(&THREE, &["-D", "-u"], "D.u")?;
+    run_mode(&THREE, &["--all-repeated=prepend"], "D.prepend")?;
+    run_mode(&THREE, &["--all-repeated=separate"], "D.separate")
+}
+
+#[test]
+fn group() -> TestResult {
+    for test in SELECTED {
+        run_mode(test, &["--group"], "group")?;
+    }
+    for method in ["prepend", "append", "both"] {
+        run_mode(
+            &THREE,
+            &[&format!("--group={}", method)],
+            &format!("group.{}", method),
+        )?;
+    }
+    run_mode(&THREE, &["--group=separate"], "group")
+}
+
+#[test]
+fn dies_meaningless_combinations() -> TestResult {
+    for args in [
+        ["-D", "-c"],
+        ["--group", "-c"],
+        ["--group", "-d"],
+        ["--group", "-u"],
+        ["--group", "-D"],
+    ] {
+        Command::cargo_bin(PRG)?
+            .args(args)
+            .arg(THREE.input)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("cannot be used with"));
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+// comparing only part of the line
+#[test]
+fn fields() -> TestResult {
+    run(&FIELDS)?;
+    run_count(&FIELDS)
+}
+
+#[test]
+fn skip_fields() -> TestResult {
+    run_mode(&FIELDS, &["-f", "2"], "f2")?;
+    run_mode(&FIELDS, &["--skip-fields", "2", "-c"], "c.f2")?;
+    run_mode(&FIELDS, &["--group", "-f", "1"], "group.f1")
+}
+
+#[test]
+fn skip_chars() -> TestResult {
+    run_mode(&FIELDS, &["-s", "11"], "s11")?;
+    run_mode(&FIELDS, &["--skip-chars=11"], "s11")?;
+    run_mode(&FIELDS, &["-f", "2", "-s", "2"], "f2.s2")
+}
+
+#[test]
+fn check_chars() -> TestResult {
+    run_mode(&FIELDS, &["-w", "3"], "w3")?;
+    run_mode(&FIELDS, &["-s", "11", "--check-chars", "2"], "s11.w2")
+}
+
+// --------------------------------------------------
+// ignoring case
+#[test]
+fn case_sensitive() -> TestResult {
+    run(&CASE)?;
+    run_count(&CASE)
+}
+
+#[test]
+fn ignore_case() -> TestResult {
+    run_mode(&CASE, &["-i"], "i")?;
+    run_mode(&CASE, &["--ignore-case", "-c"], "c.i")?;
+    // every line as it was, not folded
+    run_mode(&CASE, &["-i", "--all-repeated=separate"], "i.D.separate")
+}
+
+#[test]
+fn ignore_case_folds_fully() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-i", "-c"])
+        .write_stdin("ﬁle\nFILE\nMaße\nMASSE\nmasse\n")
+        .assert()
+        .success()
+        .stdout("   2 ﬁle\n   3 Maße\n");
+    Ok(())
+}
+
+// --------------------------------------------------
+// whitespace and bytes
+#[test]
+fn space_is_compared() -> TestResult {
+    run(&SPACE)?;
+    run_count(&SPACE)
+}
+
+#[test]
+fn ignore_trailing_space() -> TestResult {
+    run_mode(&SPACE, &["-c", "--ignore-trailing-space"], "c.trailing")
+}
+
+#[test]
+fn ignore_whitespace_changes() -> TestResult {
+    run_mode(&SPACE, &["-c", "--ignore-whitespace-changes"], "c.changes")
+}
+
+#[test]
+fn last_line_without_newline() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .write_stdin("a\nb\nb")
+        .assert()
+        .success()
+        .stdout("a\nb\n");
+    Ok(())
+}
+
+#[test]
+fn not_utf8() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-c", "-i"])
+        .write_stdin(b"x\xff\nX\xff\ny\xff\n".to_vec())
+        .assert()
+        .success()
+        .stdout(predicate::eq(b"   2 x\xff\n   1 y\xff\n".as_slice()));
+    Ok(())
+}
+
+// --------------------------------------------------
+// repeats anywhere in the input
+#[test]
+fn adjacent_only() -> TestResult {
+    run(&GLOBAL)?;
+    run_count(&GLOBAL)
+}
+
+#[test]
+fn global() -> TestResult {
+    // a 1 byte bound spills every key to disk
+    for bound in [&[][..], &["--max-memory", "1"]] {
+        let mode =
+            |args: &[&str], name| run_mode(&GLOBAL, &[&["--global"], args, bound].concat(), name);
+        mode(&[], "global")?;
+        mode(&["-c"], "c.global")?;
+        mode(&["-d"], "d.global")?;
+        mode(&["-u"], "u.global")?;
+        mode(&["-c", "-i"], "c.i.global")?;
+    }
+    Ok(())
+}
+
+#[test]
+fn sort_by_count() -> TestResult {
+    for bound in [&[][..], &["--max-memory", "1"]] {
+        let mode = |args: &[&str], name| run_mode(&GLOBAL, &[args, bound].concat(), name);
+        // equal counts stay in input order
+        mode(&["-c", "--sort-by-count"], "c.sorted")?;
+        mode(&["-c", "--sort-by-count=desc"], "c.sorted")?;
+        mode(&["-c", "--sort-by-count=asc"], "c.asc")?;
+        mode(&["-c", "-i", "--sort-by-count"], "c.i.sorted")?;
+        mode(&["-c", "--top", "2"], "c.top2")?;
+        mode(&["-c", "--sort-by-count", "--top", "2"], "c.top2")?;
+        // lines not printed don't count towards the top
+        mode(&["-u", "--top", "3"], "u.top3")?;
+    }
+    Ok(())
+}
+
+#[test]
+fn global_spills_many_files() -> TestResult {
+    let input: String = (0..5000)
+        .map(|i| format!("{}\n{}\n", i % 377, i * 7 % 1000))
+        .collect();
+    for (args, lines) in [
+        (&["--global", "-c"][..], 1000),
+        (&["--sort-by-count", "-c"], 1000),
+        (&["--top", "10", "-c"], 10),
+    ] {
+        let in_memory = Command::cargo_bin(PRG)?
+            .args(args)
+            .write_stdin(input.clone())
+            .output()?;
+        assert!(in_memory.status.success());
+        assert_eq!(
+            String::from_utf8(in_memory.stdout.clone())?.lines().count(),
+            lines
+        );
+        for bound in ["1", "10K"] {
+            Command::cargo_bin(PRG)?
+                .args(args)
+                .args(["--max-memory", bound])
+                .write_stdin(input.clone())
+                .assert()
+                .success()
+                .stdout(predicate::eq(in_memory.stdout.as_slice()));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn dies_bad_global() -> TestResult {
+    for (args, error) in [
+        (&["--global", "-D"][..], "cannot be used with"),
+        (&["--global", "--group"], "cannot be used with"),
+        (&["--sort-by-count", "-D"], "cannot be used with"),
+        (&["--top", "3", "--group"], "cannot be used with"),
+        (&["--sort-by-count=most"], "invalid value 'most'"),
+        (&["--max-memory", "1K"], "--global"),
+        (&["--global", "--max-memory", "0"], "invalid size '0'"),
+        (&["--global", "--max-memory", "3Q"], "invalid size unit 'Q'"),
+    ] {
+        Command::cargo_bin(PRG)?
+            .args(args)
+            .arg(GLOBAL.input)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(error));
+    }
+    Ok(())
+}
+
+// --------------------------------------------------
+// comparing a key taken out of the line
+#[test]
+fn whole_lines() -> TestResult {
+    for test in [&KEYS, &CSV] {
+        run(test)?;
+        run_count(test)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn key_regex() -> TestResult {
+    // lines without "user=" are compared whole
+    run_mode(&KEYS, &["-c", "--key-regex", r"user=(\w+)"], "c.regex")?;
+    run_mode(&KEYS, &["-c", "--key-regex", r"op=\w+"], "c.match")?;
+    let drop = ["-c", "--key-regex", r"user=(\w+)", "--drop-missing-key"];
+    run_mode(&KEYS, &drop, "c.regex.drop")?;
+    run_mode(
+        &KEYS,
+        &[&drop[..], &["--global"]].concat(),
+        "c.global.regex.drop",
+    )
+}
+
+#[test]
+fn key_field() -> TestResult {
+    // quoted fields may hold the delimiter and quotes
+    run_mode(&CSV, &["-c", "--key-field", "2"], "c.field2")?;
+    let drop = ["-c", "--key-field", "2", "--drop-missing-key"];
+    run_mode(&CSV, &drop, "c.field2.drop")?;
+    run_mode(&CSV, &["-D", "--key-field", "3"], "D.field3")?;
+    run_mode(
+        &CSV,
+        &["-c", "--key-field", "3", "--top", "2"],
+        "c.top2.field3",
+    )
+}
+
+#[test]
+fn key_field_delimiter() -> TestResult {
+    Command::cargo_bin(PRG)?
+        .args(["-c", "-i", "--key-field", "2", "--delimiter", ";"])
+        .write_stdin("a;X;1\nb;x;2\nc,X\n")
+        .assert()
+        .success()
+        .stdout("   2 a;X;1\n   1 c,X\n");
+    Ok(())
+}
+
+#[test]
+fn dies_bad_key() -> TestResult {
+    for (args, error) in [
+        (
+            &["--key-regex", "a", "--key-field", "2"][..],
+            "cannot be used with",
+        ),
+        (&["--key-regex", "("], "regex parse error"),
+        (&["--key-field", "0"], "invalid field number '0'"),
+        (
+            &["--key-field", "1", "--delimiter", ";;"],
+            "single ASCII character",
+        ),
+        (&["--delimiter", ";"], "--key-field"),
+        (&["--drop-missing-key"], "--key-regex"),
+    ] {
+        Command::cargo_bin(PRG)?
+            .args(args)
+            .arg(CSV.input)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(error));
+    }
+    Ok(())
+}